@@ -0,0 +1,146 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::ocirun::{ExecutableEntry, LogLevel};
+use crate::OciRun;
+
+/// Where the allowlist is read from instead of the config, e.g. the copy of
+/// the base branch for the pull request builds of forks
+pub const ALLOWLIST_ENV: &str = "OCIRUN_ALLOWLIST";
+
+/// Set to `1` for the builds of books whose changes are not reviewed yet
+pub const UNTRUSTED_ENV: &str = "OCIRUN_UNTRUSTED";
+
+/// The executions approved for untrusted builds, by their digests.
+///
+/// One digest per line, anything after it on the line is a comment,
+/// like the location written by `mdbook-ocirun approve`.
+#[derive(Debug, Clone, Default)]
+pub struct Allowlist {
+    digests: HashSet<String>,
+}
+
+impl Allowlist {
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Could not read the allowlist {}", path.display()))?;
+        Ok(Self::parse(&content))
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let digests = content
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .filter(|digest| !digest.starts_with('#'))
+            .map(String::from)
+            .collect();
+        Self { digests }
+    }
+
+    pub fn allows(&self, digest: &str) -> bool {
+        self.digests.contains(digest)
+    }
+
+    /// The allowlist approving these entries, as written by `mdbook-ocirun approve`.
+    pub fn render(entries: &[ExecutableEntry]) -> String {
+        let mut lines: Vec<String> = entries
+            .iter()
+            .map(|entry| {
+                let location = entry.chapter.as_deref().unwrap_or_default();
+                format!("{}  {location}:{}\n", entry.digest, entry.line)
+            })
+            .collect();
+        lines.dedup();
+        lines.concat()
+    }
+}
+
+/// Digest approving a directive, attributes included so none can be added unnoticed.
+pub fn directive_digest(raw: &str) -> String {
+    sha256::digest(raw.trim())
+}
+
+/// Digest approving a snippet: what runs it, its flags and its source.
+pub fn snippet_digest(image: &str, command: &str, flags: &[String], source: &str) -> String {
+    sha256::digest(format!("{image}\0{command}\0{}\0{source}", flags.join(",")))
+}
+
+impl OciRun {
+    // The placeholder of an execution left out of an untrusted build, if it is
+    pub(crate) fn unapproved(&self, digest: &str, what: &str) -> Option<String> {
+        if !self.untrusted || self.allowlist.allows(digest) {
+            return None;
+        }
+        self.log(
+            LogLevel::Normal,
+            format!("Warning: {what} not approved for untrusted builds, digest {digest}"),
+        );
+        Some(format!(
+            "not executed in an untrusted build, `{digest}` is not in the allowlist"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{directive_digest, snippet_digest, Allowlist};
+    use crate::mock::MockEngine;
+    use crate::{OciRun, OciRunConfig};
+
+    #[test]
+    pub fn test_allowlist() {
+        let approved = directive_digest("alpine echo hi ");
+        assert_eq!(approved, directive_digest("alpine echo hi"));
+        assert_ne!(approved, directive_digest("network=bridge alpine echo hi"));
+        assert_ne!(
+            snippet_digest("rust", "cargo run", &["rust".into()], "fn main() {}"),
+            snippet_digest(
+                "rust",
+                "cargo run",
+                &["rust".into()],
+                "fn main() { evil() }"
+            ),
+        );
+        let allowlist = Allowlist::parse(&format!("# approved\n\n{approved}  intro.md:3\n"));
+        assert!(allowlist.allows(&approved));
+        assert!(!allowlist.allows("# approved"));
+
+        let engine = MockEngine::new()
+            .directive("alpine", "echo hi", Ok("hi\n"))
+            .directive("alpine", "env", Ok("TOKEN=t0k3n\n"));
+        let mut preprocessor = OciRun::builder().backend(engine).build().unwrap();
+        preprocessor.untrusted = true;
+        preprocessor.allowlist = allowlist;
+        let content = "<!-- ocirun alpine echo hi -->\n<!-- ocirun alpine env -->\n";
+        assert_eq!(
+            preprocessor.run_on_content(content, ".").unwrap(),
+            format!(
+                "hi\n```console,placeholder\nnot executed in an untrusted build, `{}` is not in the allowlist\n```\n",
+                directive_digest("alpine env")
+            )
+        );
+    }
+
+    #[test]
+    pub fn test_unreadable_allowlist() {
+        let config = OciRunConfig {
+            untrusted: true,
+            allowlist: Some("missing.allow".into()),
+            ..Default::default()
+        };
+        let error = config.create_preprocessor("/book".into()).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Could not read the allowlist /book/missing.allow"
+        );
+        // Only untrusted builds read it, `approve` writes it the first time
+        let config = OciRunConfig {
+            untrusted: false,
+            ..config
+        };
+        assert!(config.create_preprocessor("/book".into()).is_ok());
+    }
+}
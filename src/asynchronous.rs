@@ -0,0 +1,362 @@
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+#[cfg(feature = "mdbook")]
+use mdbook::book::{Book, Chapter};
+use tokio::task::{spawn_blocking, JoinHandle};
+
+use crate::ocirun::{find_executables, Executable, PendingDirective, PreparedDirective};
+use crate::outcome::ExecutionResult;
+#[cfg(feature = "mdbook")]
+use crate::utils::map_chapter;
+use crate::OciRun;
+
+// Contents are run recursively, through a boxed future
+type ContentFuture<'a> = Pin<Box<dyn Future<Output = Result<String>> + Send + 'a>>;
+
+// A directive running on tokio, stopped once nothing waits for it anymore, e.g. after an error
+struct Spawned(JoinHandle<(ExecutionResult, Duration)>);
+
+impl Drop for Spawned {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// A directive of a content, from when it is found to when its output is spliced in
+enum Started {
+    // Nothing to run, hidden or not like the executions it recorded
+    Rendered { output: String, hidden: bool },
+    Spawned(PendingDirective, Spawned),
+    // By a backend which can only run it in place, once its turn comes
+    InPlace(PendingDirective),
+}
+
+/// Async versions of the entry points, for services running on tokio.
+///
+/// They take the preprocessor, and its report, in an `Arc`.
+/// The directives of a content all run at the same time, with their outputs spliced in order,
+/// each in a container run by a tokio process when the backend can run it without blocking,
+/// see [`ExecutionBackend::run_directive_async`](crate::backend::ExecutionBackend::run_directive_async).
+/// The snippets still run one after the other, each on one of the blocking threads of tokio.
+impl OciRun {
+    pub async fn run_on_content_async(
+        self: Arc<Self>,
+        content: String,
+        working_dir: String,
+    ) -> Result<String> {
+        self.run_on_content_at_async(&content, &working_dir, 0)
+            .await
+    }
+
+    pub async fn run_on_file_async(
+        self: Arc<Self>,
+        file: PathBuf,
+        working_dir: Option<PathBuf>,
+    ) -> Result<String> {
+        let content = tokio::fs::read_to_string(&file)
+            .await
+            .with_context(|| format!("Could not read {}", file.display()))?;
+        let working_dir = self.enter_file(&file, working_dir.as_deref());
+        self.run_on_content_at_async(&content, &working_dir, 0)
+            .await
+    }
+
+    /// Runs every directive and snippet of the book, returning it preprocessed.
+    /// The chapters are run one after the other.
+    #[cfg(feature = "mdbook")]
+    pub async fn run_on_book_async(self: Arc<Self>, mut book: Book) -> Result<Book> {
+        let mut chapters = take_chapters(&mut book);
+        self.start_progress(&book);
+        let mut result = Ok(());
+        for chapter in &mut chapters {
+            result = self.run_on_chapter_async(chapter).await;
+            if result.is_err() {
+                break;
+            }
+            self.progress.chapter_done();
+        }
+        self.progress.finish();
+        result?;
+        put_back(&mut book, chapters);
+        Ok(book)
+    }
+
+    /// Like [`OciRun::check_book`], with the book preprocessed.
+    #[cfg(feature = "mdbook")]
+    pub async fn check_book_async(self: Arc<Self>, mut book: Book) -> (Book, Vec<String>) {
+        let mut chapters = take_chapters(&mut book);
+        let mut errors = vec![];
+        for chapter in &mut chapters {
+            if let Err(e) = self.run_on_chapter_async(chapter).await {
+                errors.push(format!("{}: {e}", chapter.name));
+            }
+        }
+        errors.extend(self.report.failures().iter().map(ToString::to_string));
+        put_back(&mut book, chapters);
+        (book, errors)
+    }
+
+    /// Like [`OciRun::finish_build`], writing the reports on the blocking threads of tokio.
+    pub async fn finish_build_async(self: Arc<Self>) -> Result<()> {
+        spawn_blocking(move || self.finish_build())
+            .await
+            .context("ocirun task panicked or was cancelled")?
+    }
+
+    #[cfg(feature = "mdbook")]
+    async fn run_on_chapter_async(self: &Arc<Self>, chapter: &mut Chapter) -> Result<()> {
+        if let Some(working_dir) = self.enter_chapter(chapter) {
+            chapter.content = self
+                .run_on_content_at_async(&chapter.content, &working_dir, 0)
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn run_on_content_at_async<'a>(
+        self: &'a Arc<Self>,
+        content: &'a str,
+        working_dir: &'a str,
+        depth: usize,
+    ) -> ContentFuture<'a> {
+        Box::pin(async move {
+            let executables = find_executables(content);
+            // Every directive is started before the first output is awaited
+            let mut started = vec![];
+            for executable in &executables {
+                self.enter_line(content, executable, depth);
+                if let Executable::Directive(directive) = executable {
+                    started.push(self.start_directive(
+                        &directive.raw,
+                        working_dir,
+                        directive.inline,
+                    )?);
+                }
+            }
+            let mut started = started.into_iter();
+            let shared: Arc<str> = content.into();
+            let mut result = String::new();
+            let mut begin: usize = 0;
+            for executable in executables {
+                self.enter_line(content, &executable, depth);
+                match executable {
+                    Executable::Directive(directive) => {
+                        result.push_str(&content[begin..directive.range.start]);
+                        let started = started.next().expect("every directive is started");
+                        let output = self
+                            .finish_directive_async(
+                                &directive.raw,
+                                started,
+                                working_dir,
+                                directive.inline,
+                                depth,
+                            )
+                            .await?;
+                        if let Some(output) = output {
+                            result.push_str(&output);
+                        }
+                        begin = directive.range.end;
+                    }
+                    Executable::Snippet(snippet) => {
+                        let range = snippet.all_range.clone();
+                        result.push_str(&content[begin..range.start]);
+                        let recorded = self.report.recorded();
+                        let (preprocessor, shared) = (self.clone(), shared.clone());
+                        let output =
+                            spawn_blocking(move || preprocessor.run_snippet(&snippet, &shared))
+                                .await
+                                .context("ocirun snippet panicked or was cancelled")?;
+                        if !self.hides(recorded) {
+                            result.push_str(&content[range.clone()]);
+                            result.push_str(&output.unwrap_or_default());
+                        }
+                        begin = range.end;
+                    }
+                }
+            }
+            result.push_str(&content[begin..]);
+            Ok(result)
+        })
+    }
+
+    // Spawns the directive when the backend can run it without blocking
+    fn start_directive(
+        &self,
+        raw_command: &str,
+        working_dir: &str,
+        inline: bool,
+    ) -> Result<Started> {
+        let recorded = self.report.recorded();
+        let pending = match self.prepare_directive(raw_command, working_dir, inline)? {
+            PreparedDirective::Rendered(mut output) => {
+                if !inline {
+                    output = self.with_metadata(output);
+                }
+                return Ok(Started::Rendered {
+                    hidden: self.hides(recorded),
+                    output,
+                });
+            }
+            PreparedDirective::Pending(pending) => pending,
+        };
+        Ok(
+            match self
+                .backend
+                .run_directive_async(&self.directive_run(&pending))
+            {
+                Some(future) => {
+                    let handle = tokio::spawn(async move {
+                        let started = Instant::now();
+                        let result = future.await;
+                        (result, started.elapsed())
+                    });
+                    Started::Spawned(pending, Spawned(handle))
+                }
+                None => Started::InPlace(pending),
+            },
+        )
+    }
+
+    // The output of the directive, with the ones of its output when it is recursive,
+    // `None` when it is hidden
+    async fn finish_directive_async(
+        self: &Arc<Self>,
+        raw_command: &str,
+        started: Started,
+        working_dir: &str,
+        inline: bool,
+        depth: usize,
+    ) -> Result<Option<String>> {
+        let recorded = self.report.recorded();
+        let mut output = match started {
+            Started::Rendered { output, hidden } => return Ok((!hidden).then_some(output)),
+            Started::Spawned(pending, mut spawned) => {
+                let (result, duration) = (&mut spawned.0)
+                    .await
+                    .context("ocirun command panicked or was cancelled")?;
+                self.finish_directive(pending, result, duration)?
+            }
+            Started::InPlace(pending) => {
+                let started = Instant::now();
+                let result = self.backend.run_directive(&self.directive_run(&pending));
+                self.finish_directive(pending, result, started.elapsed())?
+            }
+        };
+        if !inline {
+            output = self.with_metadata(output);
+        }
+        if self.recurses(raw_command, depth)? {
+            output = self
+                .run_on_content_at_async(&output, working_dir, depth + 1)
+                .await?;
+        }
+        Ok((!self.hides(recorded)).then_some(output))
+    }
+}
+
+// The chapters of the book in order, without their sub-chapters, to be run on their own
+// as `map_chapter` can not await, then put back
+#[cfg(feature = "mdbook")]
+fn take_chapters(book: &mut Book) -> Vec<Chapter> {
+    let mut chapters = vec![];
+    let _ = map_chapter(book, &mut |chapter| {
+        let sub_items = std::mem::take(&mut chapter.sub_items);
+        chapters.push(chapter.clone());
+        chapter.sub_items = sub_items;
+        Ok(())
+    });
+    chapters
+}
+
+#[cfg(feature = "mdbook")]
+fn put_back(book: &mut Book, chapters: Vec<Chapter>) {
+    let mut chapters = chapters.into_iter();
+    let _ = map_chapter(book, &mut |chapter| {
+        if let Some(done) = chapters.next() {
+            chapter.content = done.content;
+        }
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::sync::Barrier;
+    use tokio::time::timeout;
+
+    use crate::backend::{DirectiveFuture, DirectiveRun, ExecutionBackend};
+    use crate::mock::MockEngine;
+    use crate::outcome::{ExecutionError, ExecutionOutcome, ExecutionResult};
+    use crate::snippet::CodeSnippet;
+    use crate::OciRun;
+
+    #[tokio::test]
+    pub async fn test_run_on_content_async() {
+        let engine = MockEngine::new().directive("alpine", "cat VERSION", Ok("1.0\n"));
+        let preprocessor = Arc::new(OciRun::builder().backend(engine).build().unwrap());
+        let output = preprocessor
+            .clone()
+            .run_on_content_async(
+                "Version <!-- ocirun alpine cat VERSION -->.".into(),
+                ".".into(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(output, "Version 1.0.");
+        assert_eq!(preprocessor.report.executions().len(), 1);
+    }
+
+    // Answers the image of the directives, once all of them are running
+    struct Gathering(Arc<Barrier>);
+
+    fn failure(stderr: &str) -> ExecutionResult {
+        Err(ExecutionError::Failed(ExecutionOutcome::failure(stderr, 1)))
+    }
+
+    impl ExecutionBackend for Gathering {
+        fn run_directive(&self, _directive: &DirectiveRun) -> ExecutionResult {
+            failure("run in place")
+        }
+
+        fn run_directive_async(&self, directive: &DirectiveRun) -> Option<DirectiveFuture> {
+            let barrier = self.0.clone();
+            let image = directive.image.to_string();
+            Some(Box::pin(async move {
+                match timeout(Duration::from_secs(5), barrier.wait()).await {
+                    Ok(_) => Ok(ExecutionOutcome::success(image)),
+                    Err(_) => failure("not run at the same time"),
+                }
+            }))
+        }
+
+        fn run_snippet(&self, _snippet: &CodeSnippet) -> ExecutionResult {
+            failure("no snippet")
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_directives_run_at_the_same_time() {
+        let backend = Gathering(Arc::new(Barrier::new(3)));
+        let preprocessor = Arc::new(OciRun::builder().backend(backend).build().unwrap());
+        let content = "<!-- ocirun alpine a -->, <!-- ocirun fedora b --> and\n\
+                       then <!-- ocirun ubuntu c -->.";
+        let output = preprocessor
+            .clone()
+            .run_on_content_async(content.into(), ".".into())
+            .await
+            .unwrap();
+        assert_eq!(output, "alpine, fedora and\nthen ubuntu.");
+        let executions = preprocessor.report.executions();
+        assert!(executions.iter().all(|execution| execution.success));
+        let lines: Vec<_> = executions.iter().map(|execution| execution.line).collect();
+        assert_eq!(lines, vec![Some(1), Some(1), Some(2)]);
+    }
+}
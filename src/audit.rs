@@ -0,0 +1,210 @@
+#[cfg(feature = "mdbook")]
+use std::collections::BTreeMap;
+use std::fmt;
+#[cfg(feature = "mdbook")]
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "mdbook")]
+use mdbook::book::{Book, BookItem};
+use serde::Serialize;
+
+use crate::ocirun::ExecutableEntry;
+#[cfg(feature = "mdbook")]
+use crate::parse_directives;
+#[cfg(feature = "mdbook")]
+use crate::report::ExecutionKind;
+use crate::OciRun;
+
+/// An image used by the book, with the digest it resolves to on this machine.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditImage {
+    pub name: String,
+    /// `None` when the image is not pulled yet
+    pub digest: Option<String>,
+    pub directives: usize,
+    pub snippets: usize,
+}
+
+/// A host directory mounted into the containers of the directives,
+/// or the file of a secret mounted into every container.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditMount {
+    pub host_path: String,
+    pub writable: bool,
+    pub chapters: Vec<String>,
+    /// Name of the secret, its value is never part of the audit
+    pub secret: Option<String>,
+}
+
+/// Everything a book runs and exposes, to be reviewed before it enters CI.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Audit {
+    pub engine: String,
+    pub images: Vec<AuditImage>,
+    pub mounts: Vec<AuditMount>,
+    pub commands: Vec<ExecutableEntry>,
+}
+
+impl fmt::Display for Audit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Engine: {}", self.engine)?;
+        writeln!(f, "\nImages ({}):", self.images.len())?;
+        for image in &self.images {
+            writeln!(
+                f,
+                "  {} {} ({} directives, {} snippets)",
+                image.name,
+                image.digest.as_deref().unwrap_or("<not pulled>"),
+                image.directives,
+                image.snippets
+            )?;
+        }
+        writeln!(f, "\nHost mounts ({}):", self.mounts.len())?;
+        for mount in &self.mounts {
+            let mode = match mount.writable {
+                true => "read-write",
+                false => "read-only",
+            };
+            match &mount.secret {
+                Some(name) => writeln!(
+                    f,
+                    "  {} {} (secret {name}, used by every container)",
+                    mount.host_path, mode
+                )?,
+                None => writeln!(
+                    f,
+                    "  {} {} (used by {})",
+                    mount.host_path,
+                    mode,
+                    mount.chapters.join(", ")
+                )?,
+            }
+        }
+        writeln!(f, "\nCommands ({}):", self.commands.len())?;
+        for command in &self.commands {
+            writeln!(f, "  {command}")?;
+        }
+        Ok(())
+    }
+}
+
+impl OciRun {
+    /// Digest of the image as pulled on this machine.
+    pub fn resolve_digest(&self, image: &str) -> Option<String> {
+        self.backend.image_digest(image)
+    }
+
+    #[cfg(feature = "mdbook")]
+    pub fn audit_book(&self, book: &Book) -> Audit {
+        let mut images: BTreeMap<String, AuditImage> = BTreeMap::new();
+        let mut mounts: BTreeMap<String, AuditMount> = BTreeMap::new();
+        let mut commands = vec![];
+        for item in book.iter() {
+            let BookItem::Chapter(chapter) = item else {
+                continue;
+            };
+            if self.is_ignored(chapter) {
+                continue;
+            }
+            let chapter_name = chapter
+                .source_path
+                .as_ref()
+                .map(|path| path.to_string_lossy().to_string())
+                .unwrap_or_else(|| chapter.name.clone());
+            let entries = self.list_content(&chapter.content, Some(chapter_name.clone()));
+            for entry in &entries {
+                let image = images
+                    .entry(entry.image.clone())
+                    .or_insert_with(|| AuditImage {
+                        name: entry.image.clone(),
+                        digest: None,
+                        directives: 0,
+                        snippets: 0,
+                    });
+                match entry.kind {
+                    ExecutionKind::Directive => image.directives += 1,
+                    ExecutionKind::Snippet => image.snippets += 1,
+                }
+            }
+            // Snippets get a copy of their source, only directives see the host
+            let working_dir = self.chapter_working_dir(chapter);
+            let chapter_dir = Path::new(&working_dir)
+                .canonicalize()
+                .unwrap_or(PathBuf::from(&working_dir));
+            for directive in parse_directives(&chapter.content) {
+                let host_path = self
+                    .mount_dir(&directive, &chapter_dir)
+                    .unwrap_or(chapter_dir.clone())
+                    .to_string_lossy()
+                    .to_string();
+                let mount = mounts
+                    .entry(host_path.clone())
+                    .or_insert_with(|| AuditMount {
+                        host_path,
+                        writable: false,
+                        chapters: vec![],
+                        secret: None,
+                    });
+                // Mounted read-only unless a directive asks otherwise
+                mount.writable |= directive.flag("writable").unwrap_or(false);
+                if !mount.chapters.contains(&chapter_name) {
+                    mount.chapters.push(chapter_name.clone());
+                }
+            }
+            commands.extend(entries);
+        }
+        for image in images.values_mut() {
+            image.digest = self.resolve_digest(&image.name);
+        }
+        let secrets = self.secrets.files().map(|(name, path)| AuditMount {
+            host_path: path.to_string_lossy().to_string(),
+            writable: false,
+            chapters: vec![],
+            secret: Some(name.to_string()),
+        });
+        Audit {
+            engine: self.engine.clone(),
+            images: images.into_values().collect(),
+            mounts: mounts.into_values().chain(secrets).collect(),
+            commands,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mdbook"))]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use mdbook::book::{Book, Chapter};
+
+    use crate::ocirun::SecretSource;
+    use crate::OciRunConfig;
+
+    #[test]
+    pub fn test_audit_secret_mounts() {
+        let preprocessor = OciRunConfig {
+            secrets: BTreeMap::from([(
+                "CERT".to_string(),
+                SecretSource::File("certs/cert.pem".into()),
+            )]),
+            ..Default::default()
+        }
+        .create_preprocessor("/book".into())
+        .unwrap();
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "intro",
+            "<!-- ocirun alpine cat /run/secrets/CERT -->\n".into(),
+            "intro.md",
+            vec![],
+        ));
+        let audit = preprocessor.audit_book(&book);
+        let secret = audit.mounts.last().unwrap();
+        assert_eq!(secret.host_path, "/book/certs/cert.pem");
+        assert_eq!(secret.secret.as_deref(), Some("CERT"));
+        assert!(!secret.writable);
+        assert!(audit
+            .to_string()
+            .contains("  /book/certs/cert.pem read-only (secret CERT, used by every container)\n"));
+    }
+}
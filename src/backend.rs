@@ -0,0 +1,244 @@
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::path::Path;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::ocirun::kill_container;
+#[cfg(feature = "async")]
+use crate::ocirun::kill_container_async;
+use crate::outcome::{from_captured, ExecutionResult};
+use crate::snippet::{CodeSnippet, SnippetRunner};
+#[cfg(feature = "async")]
+use crate::utils::capture_async;
+pub use crate::utils::CaptureLimits;
+use crate::utils::{capture, Redactor, Secrets};
+use crate::OciRun;
+
+/// A directive to run, with the engine arguments ocirun would run it with.
+pub struct DirectiveRun<'a> {
+    pub image: &'a str,
+    pub command: &'a str,
+    /// Host directory the command runs from, mounted at the same path
+    pub working_dir: &'a Path,
+    /// Whether the command is run by the shell of the image
+    pub shell: bool,
+    /// Name given to the container, to stop it
+    pub container: &'a str,
+    /// Arguments of the container engine, for backends speaking its command line
+    pub engine_args: &'a [String],
+    pub limits: &'a CaptureLimits,
+    /// Prefix of the output lines to print as soon as they are produced, if any
+    pub echo: Option<&'a str>,
+    /// Masks the secrets in the printed lines
+    pub redactor: &'a Redactor,
+}
+
+/// A directive run without blocking, owning everything it needs to be spawned on tokio.
+#[cfg(feature = "async")]
+pub type DirectiveFuture = Pin<Box<dyn Future<Output = ExecutionResult> + Send>>;
+
+/// Where the directives and snippets are run, a container engine by default.
+pub trait ExecutionBackend: Send + Sync {
+    fn run_directive(&self, directive: &DirectiveRun) -> ExecutionResult;
+
+    /// Runs the directive without blocking, for the async entry points,
+    /// `None` when the backend can only run it with [`ExecutionBackend::run_directive`].
+    #[cfg(feature = "async")]
+    fn run_directive_async(&self, _directive: &DirectiveRun) -> Option<DirectiveFuture> {
+        None
+    }
+
+    fn run_snippet(&self, snippet: &CodeSnippet) -> ExecutionResult;
+
+    // Whether running the snippet would only read a previous result
+    fn is_cached(&self, _snippet: &CodeSnippet) -> bool {
+        false
+    }
+
+    /// Digest of the image the executions use, `None` when it is unknown.
+    fn image_digest(&self, _image: &str) -> Option<String> {
+        None
+    }
+}
+
+// Shared with the caller, e.g. to check what a mock engine ran once the build is done
+impl<B: ExecutionBackend> ExecutionBackend for Arc<B> {
+    fn run_directive(&self, directive: &DirectiveRun) -> ExecutionResult {
+        self.as_ref().run_directive(directive)
+    }
+
+    #[cfg(feature = "async")]
+    fn run_directive_async(&self, directive: &DirectiveRun) -> Option<DirectiveFuture> {
+        self.as_ref().run_directive_async(directive)
+    }
+
+    fn run_snippet(&self, snippet: &CodeSnippet) -> ExecutionResult {
+        self.as_ref().run_snippet(snippet)
+    }
+
+    fn is_cached(&self, snippet: &CodeSnippet) -> bool {
+        self.as_ref().is_cached(snippet)
+    }
+
+    fn image_digest(&self, image: &str) -> Option<String> {
+        self.as_ref().image_digest(image)
+    }
+}
+
+/// Runs the directives with the command line of a container engine,
+/// like docker or podman, and the snippets with its snippet runner.
+pub struct OciBackend {
+    pub engine: String,
+    pub snippet_runner: Box<dyn SnippetRunner>,
+    /// Values of the secrets named in the arguments of the directives
+    pub secrets: Secrets,
+}
+
+impl OciBackend {
+    pub fn new(engine: String, snippet_runner: Box<dyn SnippetRunner>) -> Self {
+        Self {
+            engine,
+            snippet_runner,
+            secrets: Secrets::default(),
+        }
+    }
+
+    pub fn with_secrets(mut self, secrets: Secrets) -> Self {
+        self.secrets = secrets;
+        self
+    }
+}
+
+impl ExecutionBackend for OciBackend {
+    fn run_directive(&self, directive: &DirectiveRun) -> ExecutionResult {
+        let started = Instant::now();
+        let mut command = Command::new(self.engine.as_str());
+        command.args(directive.engine_args);
+        self.secrets.apply(&mut command);
+        let echo = directive.echo.map(|prefix| (prefix, directive.redactor));
+        let captured = capture(&mut command, directive.limits, echo, || {
+            kill_container(&self.engine, directive.container)
+        });
+        // Redacted by the preprocessor, which knows the rules
+        from_captured(captured, started, &Redactor::default())
+    }
+
+    #[cfg(feature = "async")]
+    fn run_directive_async(&self, directive: &DirectiveRun) -> Option<DirectiveFuture> {
+        let mut command = Command::new(self.engine.as_str());
+        command.args(directive.engine_args);
+        self.secrets.apply(&mut command);
+        let mut command = tokio::process::Command::from(command);
+        let engine = self.engine.clone();
+        let container = directive.container.to_string();
+        let limits = directive.limits.clone();
+        let echo = directive
+            .echo
+            .map(|prefix| (prefix.to_string(), directive.redactor.clone()));
+        Some(Box::pin(async move {
+            let started = Instant::now();
+            let echo = echo
+                .as_ref()
+                .map(|(prefix, redactor)| (prefix.as_str(), redactor));
+            let captured = capture_async(
+                &mut command,
+                &limits,
+                echo,
+                kill_container_async(&engine, &container),
+            )
+            .await;
+            from_captured(captured, started, &Redactor::default())
+        }))
+    }
+
+    fn run_snippet(&self, snippet: &CodeSnippet) -> ExecutionResult {
+        self.snippet_runner.run(snippet)
+    }
+
+    fn is_cached(&self, snippet: &CodeSnippet) -> bool {
+        self.snippet_runner.is_cached(snippet)
+    }
+
+    fn image_digest(&self, image: &str) -> Option<String> {
+        let output = Command::new(self.engine.as_str())
+            .args([
+                "image",
+                "inspect",
+                "--format",
+                "{{index .RepoDigests 0}}",
+                image,
+            ])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        let digest = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        match output.status.success() && !digest.is_empty() {
+            true => Some(digest),
+            false => None,
+        }
+    }
+}
+
+impl OciRun {
+    /// Runs the directives and snippets with another backend than the container engine.
+    pub fn with_backend(mut self, backend: impl ExecutionBackend + 'static) -> Self {
+        self.backend = Box::new(backend);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DirectiveRun, ExecutionBackend};
+    use crate::ocirun::LangConfig;
+    use crate::outcome::{ExecutionError, ExecutionOutcome, ExecutionResult};
+    use crate::snippet::CodeSnippet;
+    use crate::OciRunConfig;
+
+    // Echoes what it is asked to run
+    struct EchoBackend;
+
+    impl ExecutionBackend for EchoBackend {
+        fn run_directive(&self, directive: &DirectiveRun) -> ExecutionResult {
+            Ok(ExecutionOutcome::success(format!(
+                "{}: {}\n",
+                directive.image,
+                directive.command.trim()
+            )))
+        }
+
+        fn run_snippet(&self, snippet: &CodeSnippet) -> ExecutionResult {
+            Err(ExecutionError::Failed(ExecutionOutcome::failure(
+                format!(
+                    "{}: {}",
+                    snippet.config.image,
+                    snippet.source.get_content().trim()
+                ),
+                1,
+            )))
+        }
+    }
+
+    #[test]
+    pub fn test_with_backend() {
+        let preprocessor = OciRunConfig {
+            langs: vec![LangConfig::rust()],
+            ..Default::default()
+        }
+        .create_preprocessor(".".into())
+        .unwrap()
+        .with_backend(EchoBackend);
+        let content = "<!-- ocirun alpine echo hi -->\n```rust,ocirun\nfn main() {}\n```\n";
+        assert_eq!(
+            preprocessor.run_on_content(content, ".").unwrap(),
+            "alpine: echo hi\n```rust,ocirun\nfn main() {}\n```\n\
+             ```console,error\nrust: fn main() {}\n```\n"
+        );
+        assert_eq!(preprocessor.report.failures().len(), 1);
+    }
+}
@@ -0,0 +1,310 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use crate::backend::ExecutionBackend;
+use crate::hooks::ExecutionHook;
+use crate::ocirun::{
+    ImageConfig, LangConfig, LogLevel, Mode, MountScope, RedactRule, Render, ResourceLimits,
+    SecretSource, SecurityOptions, ServeMode, TrailingNewlines,
+};
+use crate::{OciRun, OciRunConfig};
+
+/// Builds an [`OciRun`] from code, with the options of the `book.toml`.
+///
+/// ```
+/// use mdbook_ocirun::{ocirun::LangConfig, OciRun};
+///
+/// let preprocessor = OciRun::builder()
+///     .engine("podman")
+///     .lang(LangConfig::rust())
+///     .root(".")
+///     .strict(true)
+///     .build()?;
+/// assert_eq!(preprocessor.engine, "podman");
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct OciRunBuilder {
+    config: OciRunConfig,
+    root: PathBuf,
+    cache_only: bool,
+    backend: Option<Box<dyn ExecutionBackend>>,
+    hooks: Vec<Box<dyn ExecutionHook>>,
+}
+
+impl Default for OciRunBuilder {
+    fn default() -> Self {
+        Self::from_config(OciRunConfig::default())
+    }
+}
+
+impl OciRun {
+    pub fn builder() -> OciRunBuilder {
+        OciRunBuilder::default()
+    }
+}
+
+impl OciRunBuilder {
+    /// Starts from a config, e.g. the one of a book.
+    pub fn from_config(config: OciRunConfig) -> Self {
+        Self {
+            config,
+            root: PathBuf::from("."),
+            cache_only: false,
+            backend: None,
+            hooks: vec![],
+        }
+    }
+
+    /// Root of the book, the paths of the reports are relative to it.
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = root.into();
+        self
+    }
+
+    pub fn engine(mut self, engine: impl Into<String>) -> Self {
+        self.config.engine = Some(engine.into());
+        self
+    }
+
+    pub fn lang(mut self, lang: LangConfig) -> Self {
+        self.config.langs.push(lang);
+        self
+    }
+
+    pub fn image(mut self, image: ImageConfig) -> Self {
+        self.config.images.push(image);
+        self
+    }
+
+    pub fn max_output_bytes(mut self, max_output_bytes: usize) -> Self {
+        self.config.max_output_bytes = Some(max_output_bytes);
+        self
+    }
+
+    pub fn max_output_lines(mut self, max_output_lines: usize) -> Self {
+        self.config.max_output_lines = Some(max_output_lines);
+        self
+    }
+
+    /// In seconds, like in the `book.toml`.
+    pub fn timeout(mut self, timeout: u64) -> Self {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    pub fn max_recursion_depth(mut self, max_recursion_depth: usize) -> Self {
+        self.config.max_recursion_depth = Some(max_recursion_depth);
+        self
+    }
+
+    pub fn trailing_newlines(mut self, trailing_newlines: TrailingNewlines) -> Self {
+        self.config.trailing_newlines = trailing_newlines;
+        self
+    }
+
+    pub fn strip_bom(mut self, strip_bom: bool) -> Self {
+        self.config.strip_bom = Some(strip_bom);
+        self
+    }
+
+    pub fn strip_invisible(mut self, strip_invisible: bool) -> Self {
+        self.config.strip_invisible = Some(strip_invisible);
+        self
+    }
+
+    /// Network of the containers which do not ask for one, `none` by default.
+    pub fn default_network(mut self, network: impl Into<String>) -> Self {
+        self.config.default_network = Some(network.into());
+        self
+    }
+
+    /// User of the directive containers: `host`, `image` or a `user[:group]` of the image.
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.config.user = Some(user.into());
+        self
+    }
+
+    /// OCI runtime of every container, like `runsc` for gVisor or `kata-runtime` for Kata Containers.
+    pub fn runtime(mut self, runtime: impl Into<String>) -> Self {
+        self.config.runtime = Some(runtime.into());
+        self
+    }
+
+    /// Resources of every container, the languages and attributes can override them.
+    pub fn limits(mut self, limits: ResourceLimits) -> Self {
+        self.config.limits = limits;
+        self
+    }
+
+    /// The seccomp profile is relative to the root of the book.
+    pub fn security(mut self, security: SecurityOptions) -> Self {
+        self.config.security = security;
+        self
+    }
+
+    /// Sources the chapters run from, relative to the book root, e.g. the ones of the original language.
+    pub fn source_dir(mut self, source_dir: impl Into<PathBuf>) -> Self {
+        self.config.source_dir = Some(source_dir.into());
+        self
+    }
+
+    /// What the directives see of the host, and run from, unless they set the `mount` attribute.
+    pub fn mount_scope(mut self, mount_scope: MountScope) -> Self {
+        self.config.mount_scope = mount_scope;
+        self
+    }
+
+    /// Passes the chapters matching the glob, under the book sources, through untouched.
+    pub fn ignore(mut self, pattern: impl Into<String>) -> Self {
+        self.config.ignore.push(pattern.into());
+        self
+    }
+
+    /// Where the outputs of the snippets are cached, relative to the book root.
+    pub fn cache_dir(mut self, cache_dir: impl Into<PathBuf>) -> Self {
+        self.config.cache_dir = Some(cache_dir.into());
+        self
+    }
+
+    /// Only runs the executions of the allowlist, relative to the book root.
+    pub fn untrusted(mut self, allowlist: impl Into<PathBuf>) -> Self {
+        self.config.untrusted = true;
+        self.config.allowlist = Some(allowlist.into());
+        self
+    }
+
+    /// Gives a secret to every container, under `name`, and masks its value in the outputs.
+    pub fn secret(mut self, name: impl Into<String>, source: SecretSource) -> Self {
+        self.config.secrets.insert(name.into(), source);
+        self
+    }
+
+    pub fn redact(mut self, rule: RedactRule) -> Self {
+        self.config.redact.push(rule);
+        self
+    }
+
+    pub fn report(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.report = Some(path.into());
+        self
+    }
+
+    pub fn junit(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.junit = Some(path.into());
+        self
+    }
+
+    pub fn log_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.log_file = Some(path.into());
+        self
+    }
+
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.config.strict = strict;
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.config.mode = mode;
+        self
+    }
+
+    pub fn log_level(mut self, log_level: LogLevel) -> Self {
+        self.config.log_level = log_level;
+        self
+    }
+
+    pub fn stream_output(mut self, stream_output: bool) -> Self {
+        self.config.stream_output = stream_output;
+        self
+    }
+
+    pub fn metadata(mut self, metadata: bool) -> Self {
+        self.config.metadata = metadata;
+        self
+    }
+
+    /// Only applies under `mdbook serve`, see [`OciRunBuilder::cache_only`] otherwise.
+    pub fn serve_mode(mut self, serve_mode: ServeMode) -> Self {
+        self.config.serve_mode = serve_mode;
+        self
+    }
+
+    /// Renders placeholders instead of running what is not cached yet.
+    pub fn cache_only(mut self, cache_only: bool) -> Self {
+        self.cache_only = cache_only;
+        self
+    }
+
+    pub fn render(mut self, render: Render) -> Self {
+        self.config.render = render;
+        self
+    }
+
+    /// Runs everything with this backend instead of the container engine.
+    pub fn backend(mut self, backend: impl ExecutionBackend + 'static) -> Self {
+        self.backend = Some(Box::new(backend));
+        self
+    }
+
+    /// Calls the hook around every execution, after the hooks already added.
+    pub fn hook(mut self, hook: impl ExecutionHook + 'static) -> Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Fails on options it can not use, like an invalid ignore pattern.
+    pub fn build(self) -> Result<OciRun> {
+        let mut preprocessor = self.config.create_preprocessor(self.root)?;
+        preprocessor.cache_only = self.cache_only;
+        preprocessor.hooks = self.hooks;
+        if let Some(backend) = self.backend {
+            preprocessor.backend = backend;
+        }
+        Ok(preprocessor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::ocirun::{LangConfig, Mode};
+    use crate::{OciRun, OciRunConfig};
+
+    #[test]
+    pub fn test_builder() {
+        let preprocessor = OciRun::builder()
+            .engine("podman")
+            .lang(LangConfig::rust())
+            .root("book")
+            .report("target/ocirun.json")
+            .timeout(60)
+            .strict(true)
+            .mode(Mode::DryRun)
+            .build()
+            .unwrap();
+        let expected = OciRunConfig {
+            engine: Some("podman".into()),
+            langs: vec![LangConfig::rust()],
+            report: Some("target/ocirun.json".into()),
+            timeout: Some(60),
+            strict: true,
+            mode: Mode::DryRun,
+            ..Default::default()
+        }
+        .create_preprocessor("book".into())
+        .unwrap();
+        assert_eq!(preprocessor.engine, expected.engine);
+        assert_eq!(preprocessor.langs, expected.langs);
+        assert_eq!(preprocessor.root_path, PathBuf::from("book"));
+        assert_eq!(preprocessor.report_path, expected.report_path);
+        assert_eq!(preprocessor.capture_limits, expected.capture_limits);
+        assert!(preprocessor.strict);
+        assert_eq!(preprocessor.mode, Mode::DryRun);
+
+        // Running the drafts meant to be skipped would be worse than not building
+        assert!(OciRun::builder().ignore("drafts/[").build().is_err());
+    }
+}
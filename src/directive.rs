@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use anyhow::{bail, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::ocirun::{find_executables, Executable};
+
+lazy_static! {
+    static ref OCIRUN_DIRECTIVE: Regex = Regex::new(r"<!--[ ]*ocirun (.*?)-->(\r?\n)?")
+        .expect("Failed to init regex for finding directive pattern");
+}
+
+const DEFAULT_IMAGE: &str = "alpine";
+
+/// A parsed `<!-- ocirun [key=value ...] image command -->` directive.
+///
+/// Leading `key=value` words are attributes tweaking how the directive is run,
+/// the next word is the image and everything after it is the command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Directive {
+    pub attributes: BTreeMap<String, String>,
+    pub image: String,
+    pub command: String,
+    /// Bytes of the whole comment in the content, with its line break for block directives
+    pub range: Range<usize>,
+    /// Whether the output replaces it within its line, rather than as a block
+    pub inline: bool,
+}
+
+/// The directives of a markdown content ocirun would run, in order,
+/// without the ones written inside executable snippets as they are part of their source.
+pub fn parse_directives(content: &str) -> Vec<Directive> {
+    // The same scan as the build, so only the directives it runs are found
+    find_executables(content)
+        .into_iter()
+        .filter_map(|executable| match executable {
+            Executable::Directive(directive) => Some(directive),
+            Executable::Snippet(_) => None,
+        })
+        .map(|directive| Directive {
+            range: directive.range,
+            inline: directive.inline,
+            ..Directive::parse(&directive.raw)
+        })
+        .collect()
+}
+
+impl Directive {
+    // Parses what is between `<!-- ocirun` and `-->`, the position is left empty
+    pub(crate) fn parse(raw: &str) -> Directive {
+        let mut attributes = BTreeMap::new();
+        let mut rest = raw.trim_start();
+        while let Some((word, tail)) = rest.split_once(' ') {
+            match word.split_once('=') {
+                Some((key, value)) if !key.is_empty() => {
+                    attributes.insert(key.to_string(), value.to_string());
+                    rest = tail.trim_start();
+                }
+                _ => break,
+            }
+        }
+        let (image, command) = rest.split_once(' ').unwrap_or((DEFAULT_IMAGE, rest));
+        Directive {
+            attributes,
+            image: image.to_string(),
+            command: command.to_string(),
+            range: 0..0,
+            inline: false,
+        }
+    }
+
+    pub fn flag(&self, name: &str) -> Result<bool> {
+        match self.attributes.get(name).map(String::as_str) {
+            None | Some("false") => Ok(false),
+            Some("true") => Ok(true),
+            Some(value) => bail!("Invalid value `{value}` for ocirun attribute `{name}`"),
+        }
+    }
+}
+
+/// Where a directive was found in a markdown content.
+///
+/// A directive followed by a line break is a block directive, and the
+/// line break is part of its range, otherwise it is an inline directive.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DirectiveRef {
+    pub raw: String,
+    pub range: Range<usize>,
+    pub inline: bool,
+}
+
+pub(crate) fn find_directives(content: &str) -> Vec<DirectiveRef> {
+    OCIRUN_DIRECTIVE
+        .captures_iter(content)
+        .map(|caps| DirectiveRef {
+            raw: caps[1].to_string(),
+            range: caps.get(0).unwrap().range(),
+            inline: caps.get(2).is_none(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_directives, parse_directives, Directive};
+    use crate::ocirun::{find_executables, Executable};
+
+    #[test]
+    pub fn test_parse_directive() {
+        let directive = Directive::parse("python python script.py ");
+        assert!(directive.attributes.is_empty());
+        assert_eq!(directive.image, "python");
+        assert_eq!(directive.command, "python script.py ");
+
+        let directive = Directive::parse("recursive=true python python script.py --a=b");
+        assert_eq!(directive.attributes["recursive"], "true");
+        assert!(directive.flag("recursive").unwrap());
+        assert_eq!(directive.image, "python");
+        assert_eq!(directive.command, "python script.py --a=b");
+
+        let directive = Directive::parse("ls");
+        assert_eq!(directive.image, "alpine");
+        assert_eq!(directive.command, "ls");
+        assert!(!directive.flag("recursive").unwrap());
+    }
+
+    #[test]
+    pub fn test_find_directives() {
+        let content = "# Title\n<!-- ocirun alpine ls -->\nsome <!-- ocirun echo 1 --> text\r\n";
+        let directives = find_directives(content);
+        assert_eq!(directives.len(), 2);
+        assert_eq!(directives[0].raw, "alpine ls ");
+        assert_eq!(
+            &content[directives[0].range.clone()],
+            "<!-- ocirun alpine ls -->\n"
+        );
+        assert!(!directives[0].inline);
+        assert_eq!(directives[1].raw, "echo 1 ");
+        assert!(directives[1].inline);
+    }
+
+    #[test]
+    pub fn test_parse_directives() {
+        let content = "<!-- ocirun shell=false alpine ls -->\n\
+                       ```bash,ocirun\n<!-- ocirun alpine echo source -->\n```\n\
+                       Version <!-- ocirun rust rustc --version -->.\n";
+        let directives = parse_directives(content);
+        assert_eq!(directives.len(), 2);
+        assert_eq!(directives[0].attributes["shell"], "false");
+        assert_eq!(directives[0].image, "alpine");
+        assert_eq!(directives[0].command, "ls ");
+        assert_eq!(directives[0].range, 0..38);
+        assert!(!directives[0].inline);
+        assert_eq!(directives[1].image, "rust");
+        assert_eq!(directives[1].command, "rustc --version ");
+        assert_eq!(
+            &content[directives[1].range.clone()],
+            "<!-- ocirun rust rustc --version -->"
+        );
+        assert!(directives[1].inline);
+    }
+
+    #[test]
+    pub fn test_parse_directives_in_snippets() {
+        let content = "```rust,ocirun\nfn main() {}\n<!-- ocirun alpine echo source -->\n```\n\
+                       ```sh\n<!-- ocirun alpine echo plain -->\n```\n\
+                       <!-- ocirun alpine echo after -->\n";
+        let directives = parse_directives(content);
+        let commands: Vec<&str> = directives.iter().map(|it| it.command.as_str()).collect();
+        assert_eq!(commands, ["echo plain ", "echo after "]);
+        // Exactly the directives the build runs
+        let run: Vec<_> = find_executables(content)
+            .into_iter()
+            .filter_map(|executable| match executable {
+                Executable::Directive(directive) => Some(directive.range),
+                Executable::Snippet(_) => None,
+            })
+            .collect();
+        let ranges: Vec<_> = directives.into_iter().map(|it| it.range).collect();
+        assert_eq!(ranges, run);
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use mdbook::book::{Book, BookItem};
+use serde::Serialize;
+
+use crate::OciRun;
+
+/// How a preprocessed chapter differs from its expected content.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum GoldenDiff {
+    /// There is no expected file for the chapter
+    Missing { chapter: PathBuf },
+    /// The chapter differs from the expected file, from this line on
+    Differs {
+        chapter: PathBuf,
+        line: usize,
+        expected: Option<String>,
+        actual: Option<String>,
+    },
+    /// An expected file without a chapter
+    Unexpected { path: PathBuf },
+}
+
+impl fmt::Display for GoldenDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GoldenDiff::Missing { chapter } => {
+                write!(f, "{}: no expected content", chapter.display())
+            }
+            GoldenDiff::Differs {
+                chapter,
+                line,
+                expected,
+                actual,
+            } => {
+                write!(
+                    f,
+                    "{}:{line}: differs from the expected content",
+                    chapter.display()
+                )?;
+                if expected.is_none() && actual.is_none() {
+                    return write!(f, "\n  the line breaks at the end differ");
+                }
+                let line = |line: &Option<String>| line.clone().unwrap_or("<end of file>".into());
+                write!(f, "\n  - {}\n  + {}", line(expected), line(actual))
+            }
+            GoldenDiff::Unexpected { path } => {
+                write!(f, "{}: expected content of no chapter", path.display())
+            }
+        }
+    }
+}
+
+// The first line from which `actual` differs, `None` if it does not
+fn first_difference(
+    expected: &str,
+    actual: &str,
+) -> Option<(usize, Option<String>, Option<String>)> {
+    let mut expected_lines = expected.lines();
+    let mut actual_lines = actual.lines();
+    let mut line = 1;
+    loop {
+        match (expected_lines.next(), actual_lines.next()) {
+            (None, None) if expected == actual => return None,
+            (None, None) => return Some((line, None, None)),
+            (expected, actual) if expected == actual => line += 1,
+            (expected, actual) => {
+                return Some((line, expected.map(String::from), actual.map(String::from)))
+            }
+        }
+    }
+}
+
+// Every file under `dir`, relative to it
+fn files(dir: &Path, prefix: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    if !dir.is_dir() {
+        return Ok(files);
+    }
+    for entry in fs::read_dir(dir).with_context(|| format!("Could not read {}", dir.display()))? {
+        let entry = entry?;
+        let path = prefix.join(entry.file_name());
+        match entry.file_type()?.is_dir() {
+            true => files.extend(self::files(&entry.path(), &path)?),
+            false => files.push(path),
+        }
+    }
+    Ok(files)
+}
+
+impl OciRun {
+    /// Preprocesses the book then compares each chapter with the file at the same path
+    /// under `expected_dir`, or writes them there when `update` is set.
+    pub fn golden_book(
+        &self,
+        book: &mut Book,
+        expected_dir: &Path,
+        update: bool,
+    ) -> Result<Vec<GoldenDiff>> {
+        // Failed executions render their error, which is compared too
+        let _ = self.check_book(book);
+        let mut diffs = vec![];
+        let mut chapters = vec![];
+        for item in book.iter() {
+            let BookItem::Chapter(chapter) = item else {
+                continue;
+            };
+            let Some(source_path) = &chapter.source_path else {
+                continue;
+            };
+            chapters.push(source_path.clone());
+            let path = expected_dir.join(source_path);
+            if update {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&path, &chapter.content)
+                    .with_context(|| format!("Could not write {}", path.display()))?;
+                continue;
+            }
+            if !path.exists() {
+                diffs.push(GoldenDiff::Missing {
+                    chapter: source_path.clone(),
+                });
+                continue;
+            }
+            let expected = fs::read_to_string(&path)
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            if let Some((line, expected, actual)) = first_difference(&expected, &chapter.content) {
+                diffs.push(GoldenDiff::Differs {
+                    chapter: source_path.clone(),
+                    line,
+                    expected,
+                    actual,
+                });
+            }
+        }
+        let mut unexpected: Vec<PathBuf> = files(expected_dir, Path::new(""))?
+            .into_iter()
+            .filter(|path| !chapters.contains(path))
+            .collect();
+        unexpected.sort();
+        diffs.extend(
+            unexpected
+                .into_iter()
+                .map(|path| GoldenDiff::Unexpected { path }),
+        );
+        Ok(diffs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use mdbook::book::{Book, BookItem, Chapter};
+
+    use super::GoldenDiff;
+    use crate::OciRunConfig;
+
+    #[test]
+    pub fn test_golden_book() {
+        let expected_dir =
+            std::env::temp_dir().join(format!("ocirun-golden-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&expected_dir);
+        let mut book = Book::new();
+        for name in ["intro", "usage"] {
+            let content = format!("# {name}\n\nNothing to run\n");
+            let path = PathBuf::from(format!("{name}.md"));
+            book.push_item(BookItem::Chapter(Chapter::new(
+                name,
+                content,
+                &path,
+                vec![],
+            )));
+        }
+        let preprocessor = OciRunConfig::default()
+            .create_preprocessor(".".into())
+            .unwrap();
+
+        let diffs = preprocessor
+            .golden_book(&mut book.clone(), &expected_dir, false)
+            .unwrap();
+        assert_eq!(
+            diffs,
+            vec![
+                GoldenDiff::Missing {
+                    chapter: "intro.md".into()
+                },
+                GoldenDiff::Missing {
+                    chapter: "usage.md".into()
+                },
+            ]
+        );
+
+        preprocessor
+            .golden_book(&mut book.clone(), &expected_dir, true)
+            .unwrap();
+        fs::write(
+            expected_dir.join("usage.md"),
+            "# usage\n\nSomething to run\n",
+        )
+        .unwrap();
+        fs::write(expected_dir.join("old.md"), "").unwrap();
+        let diffs = preprocessor
+            .golden_book(&mut book, &expected_dir, false)
+            .unwrap();
+        fs::remove_dir_all(&expected_dir).unwrap();
+        assert_eq!(
+            diffs,
+            vec![
+                GoldenDiff::Differs {
+                    chapter: "usage.md".into(),
+                    line: 3,
+                    expected: Some("Something to run".into()),
+                    actual: Some("Nothing to run".into()),
+                },
+                GoldenDiff::Unexpected {
+                    path: "old.md".into()
+                },
+            ]
+        );
+        assert_eq!(
+            diffs[0].to_string(),
+            "usage.md:3: differs from the expected content\n  - Something to run\n  + Nothing to run"
+        );
+    }
+}
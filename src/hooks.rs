@@ -0,0 +1,116 @@
+use crate::report::{Execution, ExecutionKind, FailureKind};
+use crate::OciRun;
+
+/// Called around every execution, e.g. for metrics, policy checks or notifications.
+pub trait ExecutionHook: Send + Sync {
+    /// Before running, with only the kind, location, image and command of the execution set.
+    /// An error refuses it: it fails with this error without being run.
+    fn before(&self, _execution: &Execution) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Once recorded in the report, cached snippets included.
+    fn after(&self, _execution: &Execution) {}
+}
+
+impl OciRun {
+    /// Calls the hook around every execution, after the hooks already added.
+    pub fn with_hook(mut self, hook: impl ExecutionHook + 'static) -> Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    // Asks the hooks whether it can be run, recording the refusal if one of them refuses
+    pub(crate) fn refusal(
+        &self,
+        kind: ExecutionKind,
+        image: &str,
+        command: &str,
+    ) -> Option<String> {
+        if self.hooks.is_empty() {
+            return None;
+        }
+        let mut execution = Execution::new(kind, image, command);
+        self.report.locate(&mut execution);
+        let error = self
+            .hooks
+            .iter()
+            .find_map(|hook| hook.before(&execution).err())?;
+        self.record(Execution {
+            error: Some(error.clone()),
+            failure: Some(FailureKind::Execution),
+            ..execution
+        });
+        Some(error)
+    }
+
+    // Records the execution in the report then hands it to the hooks
+    pub(crate) fn record(&self, execution: Execution) {
+        self.report.record(execution);
+        if let Some(execution) = self.report.last() {
+            for hook in &self.hooks {
+                hook.after(&execution);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::ExecutionHook;
+    use crate::ocirun::LangConfig;
+    use crate::report::{Execution, ExecutionKind};
+    use crate::OciRun;
+
+    // Refuses the snippets, and keeps what it was called with
+    #[derive(Default)]
+    struct NoSnippets {
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl ExecutionHook for NoSnippets {
+        fn before(&self, execution: &Execution) -> Result<(), String> {
+            self.calls.lock().unwrap().push(format!(
+                "before {}:{}",
+                execution.chapter.as_deref().unwrap_or_default(),
+                execution.line.unwrap_or_default()
+            ));
+            match execution.kind {
+                ExecutionKind::Snippet => Err("snippets are not allowed".into()),
+                ExecutionKind::Directive => Ok(()),
+            }
+        }
+
+        fn after(&self, execution: &Execution) {
+            self.calls.lock().unwrap().push(format!(
+                "after {}",
+                execution.error.as_deref().unwrap_or("success")
+            ));
+        }
+    }
+
+    #[test]
+    pub fn test_hooks() {
+        let hook = NoSnippets::default();
+        let calls = hook.calls.clone();
+        let preprocessor = OciRun::builder()
+            .lang(LangConfig::rust())
+            .hook(hook)
+            .build()
+            .unwrap();
+        preprocessor.report.enter_chapter(Some("intro.md".into()));
+        let content = "# Intro\n\n```rust,ocirun\nfn main() {}\n```\n";
+        assert_eq!(
+            preprocessor.run_on_content(content, ".").unwrap(),
+            "# Intro\n\n```rust,ocirun\nfn main() {}\n```\n\
+             ```console,error\nsnippets are not allowed\n```\n"
+        );
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["before intro.md:3", "after snippets are not allowed"]
+        );
+        assert_eq!(preprocessor.report.failures().len(), 1);
+    }
+}
@@ -72,6 +72,47 @@
 //! Also the working directory is the directory where the pattern was found (not root).
 //! The command invoked must take no inputs (stdin is not used), but a list of command lines arguments and must produce output in stdout, stderr is ignored.
 //!
+//! The output of a command is inserted as it is, even if it contains other `ocirun` directives or snippets.
+//! To process them too, add the `recursive=true` attribute before the image: `<!-- ocirun recursive=true <image> $1 -->`.
+//!
+//! Images without a shell, like distroless ones, can't run `sh -c $1`.
+//! For them, add the `shell=false` attribute, or configure the image once, and the words of `$1` are given directly to the container:
+//!
+//! ```toml
+//! [[preprocessor.ocirun.images]]
+//! name = "gcr.io/distroless/python3"
+//! shell = false
+//! ```
+//!
+//! The directory of the chapter is mounted read-only, so a command can not change the book sources by mistake.
+//! The rare commands writing there, like compilers leaving a binary next to the source, need the `writable=true` attribute:
+//! `<!-- ocirun writable=true rust rustc script.rs; ./script; rm script -->`.
+//! Scripts shared by the chapters, e.g. in a `tools/` directory at the book root, are reached by mounting a wider directory,
+//! which the command then runs from: `<!-- ocirun mount=root python python tools/gen.py -->`, or `mount_scope = "root"`
+//! for every directive. `mount=src` mounts the book sources.
+//!
+//! Containers have no network by default, a directive needing one asks for it with the `network` attribute,
+//! e.g. `<!-- ocirun network=bridge alpine wget -qO- example.com -->`, and the snippets of a language with
+//! `network = "bridge"` in its `langs` entry. To give every container the network of the engine again, like before, set
+//! `default_network = "bridge"`.
+//!
+//! On unix the directives run as the user building the book, with `--userns keep-id` on podman and
+//! `--user uid:gid` on the other engines, so the files they write in `writable=true` chapters are not owned by root.
+//! Set `user = "image"` for images which need their own user.
+//!
+//! The translations of a book, e.g. with mdbook-i18n-helpers, can be built from their own sources, like `src-da`,
+//! while referencing the scripts and data of the original ones. With `source_dir = "src"` in the config of the
+//! translations, each chapter runs from its directory in `src`, or from its own when it only exists in the translation.
+//! Every translation then runs against the same files, and shares the cached results of the snippets it did not change.
+//!
+//! A repository building several books shares their options, like the `langs`, `images` and `cache_dir`, in a workspace file
+//! named by the `workspace` option of each book. Each book still runs its chapters from its own sources, the `src` of its
+//! `book.toml` or the one given by mdbook, whatever the directory it is built from.
+//!
+//! API keys powering live examples go in `[preprocessor.ocirun.secrets]`. Their values are given to the engine
+//! through its environment, so they never show up in the printed commands, they are not part of the cache keys,
+//! and they are replaced by `[REDACTED]` in the outputs before these are rendered or cached.
+//!
 //! ## Examples
 //!
 //! The following is valid:
@@ -158,9 +199,328 @@
 //! Hello World
 //! ```
 //!
+//! A snippet can be followed by the output it must print, in a block flagged `expected`,
+//! or `expected-error` when it must fail:
+//!
+//! ````markdown
+//! ```python,ocirun
+//! print('Hello World')
+//! ```
+//! ```console,expected
+//! Hello World
+//! ```
+//! ````
+//!
+//! The snippet fails when its output is different, an empty `expected-error` block only requires it to fail.
+//! `mdbook test` runs the snippets too, and lists them with the Rust doctests, failing if any of them fails.
+//!
+//! The failures of experimental languages can be made warnings, their snippets still render
+//! the error but never fail the build, even with `strict = true`. They are not counted as failures
+//! in the summary, and the JUnit report gives them as `<system-err>` rather than `<failure>`:
+//!
+//! ```toml
+//! [[preprocessor.ocirun.langs]]
+//! name = "zig"
+//! image = "ziglang/zig"
+//! command = ["zig", "run", "source"]
+//! severity = "warn"
+//! ```
+//!
+//! # Configuration
+//!
+//! Besides the `langs`, these options are available under `[preprocessor.ocirun]`:
+//!
+//! ```toml
+//! [preprocessor.ocirun]
+//! # container engine used to run everything
+//! engine = "podman"
+//! # outputs bigger than this are not rendered, the container is stopped instead
+//! max_output_bytes = 1048576
+//! # same for outputs with more lines than this (no limit by default)
+//! max_output_lines = 1000
+//! # commands still running after this many seconds are stopped (no limit by default)
+//! timeout = 60
+//! # how many times the output of `recursive=true` directives can be processed again
+//! max_recursion_depth = 3
+//! # keep the trailing line breaks of block outputs ("preserve"), only one ("single") or none ("strip")
+//! trailing_newlines = "preserve"
+//! # remove byte order marks from the outputs
+//! strip_bom = true
+//! # remove invisible characters too, like zero-width spaces and control characters
+//! strip_invisible = false
+//! # mask the values of these environment variables, and the matches of the `re:` regexes,
+//! # in the outputs before they are rendered or cached
+//! redact = ["GITHUB_TOKEN", "re:sk-[A-Za-z0-9]+"]
+//! # write a JSON report of every execution there, relative to the book root
+//! report = "target/ocirun.json"
+//! # write a JUnit report there too, with a test case per execution
+//! junit = "target/ocirun.xml"
+//! # write a log of every execution there, with its time, duration, cache status
+//! # and the beginning of its output, to debug CI builds from their artifacts
+//! log_file = "target/ocirun.log"
+//! # memory, CPUs and processes every container can use (no limit by default),
+//! # a language overrides them with its own `limits`, a directive or snippet with
+//! # the `memory=`, `cpus=` and `pids=` attributes, e.g. <!-- ocirun pids=256 alpine make -->
+//! # or ```rust,ocirun,memory=2g
+//! limits = { memory = "512m", cpus = 1.0, pids = 128 }
+//! # hardening of every container: capabilities to drop, seccomp profile relative to the book root,
+//! # and whether the processes can gain privileges
+//! security = { cap_drop = ["ALL"], seccomp = "profile.json", no_new_privileges = true }
+//! # network of the containers which do not ask for one with `network=`
+//! default_network = "none"
+//! # user running the directives: "host" (the default on unix) for the user building the book,
+//! # so the files they write are not owned by root, "image" for the user of the image,
+//! # or any `user[:group]` given to the engine
+//! user = "host"
+//! # OCI runtime of every container, e.g. "runsc" (gVisor) or "kata-runtime" (Kata Containers)
+//! # for a kernel-level isolation of the code contributed by readers, the one of the engine by default
+//! runtime = "runsc"
+//! # secrets given to every container, from an environment variable of the host (as an
+//! # environment variable of the same name) or a file relative to the book root (mounted
+//! # read-only at /run/secrets/<name>), their values are masked in the outputs
+//! secrets = { API_KEY = { env = "OPENAI_API_KEY" }, CERT = { file = "certs/cert.pem" } }
+//! # only run the executions approved in this file, relative to the book root,
+//! # also enabled by setting OCIRUN_UNTRUSTED=1, e.g. for the pull requests of forks
+//! untrusted = false
+//! allowlist = "ocirun.allow"
+//! # sources the chapters run from, relative to the book root, when their directory exists there,
+//! # e.g. the ones of the original language for translations built from their own sources
+//! source_dir = "src"
+//! # where the outputs of the snippets are cached, relative to the book root, ~/.mdbook/ocirun/ by default
+//! cache_dir = "../.ocirun-cache"
+//! # options shared by the books of a repository, in a file with the keys of this table,
+//! # the ones set here win, and the paths in the file are relative to it
+//! workspace = "../ocirun.toml"
+//! # what the directives see of the host, mounted into their containers and run from:
+//! # "chapter" for the directory of their chapter, "src" for the book sources, "root" for the book root,
+//! # a directive sets its own with the `mount` attribute, e.g. <!-- ocirun mount=root python tools/gen.py -->
+//! mount_scope = "chapter"
+//! # chapters passed through untouched, nothing in them is run, matched by their path under the
+//! # book sources, with the patterns of a .ocirunignore file at the book root, one per line,
+//! # an invalid pattern fails the build
+//! ignore = ["drafts/**", "appendix-raw.md"]
+//! # fail the build when a directive or a snippet fails
+//! strict = false
+//! # "dry-run" prints the engine commands instead of running them,
+//! # and renders them in place of the outputs to preview the layout
+//! mode = "run"
+//! # what is printed on stderr while building: "quiet" for errors only,
+//! # "normal" for failures and the summary, "verbose" for every command run,
+//! # "debug" for the engine commands too
+//! log_level = "normal"
+//! # print the output of the commands on stderr while they run, each line
+//! # prefixed with the chapter and line of its directive or snippet, and redacted
+//! stream_output = false
+//! # add an HTML comment after each generated block with the image digest,
+//! # the duration and the time of the execution, hidden in the rendered pages:
+//! # <!-- ocirun: rust@sha256:..., 3.2s, cached, 2024-05-01T09:30:00Z -->
+//! metadata = false
+//! # "cache-only" keeps `mdbook serve` from waiting on containers: cached snippet
+//! # outputs are rendered, placeholders everything else
+//! serve_mode = "run"
+//! # "failures-only" removes the successful directives and snippets with their outputs,
+//! # leaving a short page of everything broken, with the errors
+//! render = "all"
+//! ```
+//!
+//! Once built, the totals are printed with a line per chapter counting its passed, cached and failed executions,
+//! green, yellow when only warnings failed, or red, unless `NO_COLOR` is set or the output is not a terminal.
+//!
+//! When running in GitHub Actions, failed directives and snippets are also reported as error annotations on the markdown sources.
+//!
+//! Where there is no container engine, e.g. on a docs hosting platform, set `OCIRUN_SKIP=1`
+//! to render the book with a placeholder in place of every output, without running anything.
+//!
+//! The pull requests of forks can change the book, and so what it runs. Their builds are marked untrusted with
+//! `OCIRUN_UNTRUSTED=1`, then only the directives and snippets whose digest is in the `allowlist` are run, every other
+//! one is rendered as a placeholder giving its digest. A directive is approved with its attributes, a snippet with its
+//! image, command, flags and source. As the fork can change the allowlist too, point `OCIRUN_ALLOWLIST` to the one of
+//! the base branch. An allowlist which can not be read fails the build rather than approving nothing.
+//!
+//! # Command line
+//!
+//! To iterate on a single chapter without building the whole book, run it alone,
+//! the config of the `book.toml` in the current directory is used:
+//!
+//! ```sh
+//! mdbook-ocirun run src/chapter.md [--working-dir DIR] [--dry-run]
+//! ```
+//!
+//! To test that everything in the book still runs, without rendering it, like in a CI pipeline.
+//! All the failures are listed, and the exit code is not zero if there is any:
+//!
+//! ```sh
+//! mdbook-ocirun check [BOOK_DIR]
+//! ```
+//!
+//! The exit code tells what kind of failure there was, the most fundamental one if there are several:
+//!
+//! | Code | Failure |
+//! |------|---------|
+//! | 0 | Everything ran as expected |
+//! | 1 | A command or a snippet failed |
+//! | 2 | Invalid command line arguments |
+//! | 3 | The book or its config could not be loaded |
+//! | 4 | The container engine is not available |
+//! | 5 | An image could not be pulled |
+//! | 6 | An output differs from the expected one |
+//! | 7 | The report, junit or log file could not be written |
+//!
+//! With `serve_mode = "cache-only"`, the snippets are cached before previewing the book by:
+//!
+//! ```sh
+//! mdbook-ocirun warm [BOOK_DIR]
+//! ```
+//!
+//! To review what a book will run before building it, with the chapter, line, image and command of everything:
+//!
+//! ```sh
+//! mdbook-ocirun list [BOOK_DIR] [--json]
+//! ```
+//!
+//! For a security review, the distinct images with the digests they resolve to,
+//! the host directories and secret files mounted into the containers and the commands run by the book are summed up by:
+//!
+//! ```sh
+//! mdbook-ocirun audit [BOOK_DIR] [--json]
+//! ```
+//!
+//! Once reviewed, everything the book runs is approved for untrusted builds, in its `allowlist`, by:
+//!
+//! ```sh
+//! mdbook-ocirun approve [BOOK_DIR]
+//! ```
+//!
+//! To find the examples which are not run or have no expected output yet, the code blocks of each chapter are counted by:
+//!
+//! ```sh
+//! mdbook-ocirun stats [BOOK_DIR] [--json]
+//! ```
+//!
+//! To test the preprocessed content of a book, like the regression tests of this repository,
+//! its chapters are compared with the ones of an expected directory, at the same paths as in the book sources.
+//! `--update` writes them there instead, to review their changes with the ones of the book:
+//!
+//! ```sh
+//! mdbook-ocirun golden [BOOK_DIR] --expected DIR [--update]
+//! ```
+//!
+//! The same comparison is available to Rust tests through `OciRun::golden_book`.
+//!
+//! To find out which engine, images and options are actually used, with the defaults filled in:
+//!
+//! ```sh
+//! mdbook-ocirun inspect-config [BOOK_DIR]
+//! ```
+//!
+//! The `-q`, `-v` and `-vv` flags of every subcommand win over the `log_level` of the book.
+//!
+//! Every subcommand has a `--help`, and the completion script of bash, zsh, fish, elvish or powershell is printed by:
+//!
+//! ```sh
+//! mdbook-ocirun completions bash > ~/.local/share/bash-completion/completions/mdbook-ocirun
+//! ```
+//!
+//! # Library
+//!
+//! Tools running only markdown contents and files, without mdbook, depend on it without the default features,
+//! which leaves out mdbook, the command line and everything working on a `Book`:
+//!
+//! ```toml
+//! [dependencies]
+//! mdbook-ocirun = { version = "0.2", default-features = false }
+//! ```
+//!
+//! The `mdbook` feature brings the books back, and `cli`, the default, the `mdbook-ocirun` command.
+//!
+//! The `async` feature adds async versions of the entry points, like `run_on_content_async` and
+//! `run_on_book_async`, in the `asynchronous` module, for services running on tokio. They take the preprocessor
+//! in an `Arc`. The directives of a content all run at the same time, each in a container run by a tokio process,
+//! and their outputs are spliced in order. The snippets still run one after the other, on the blocking threads
+//! of tokio. Backends run the directives without blocking by implementing `run_directive_async`.
+//!
+//! Tools embedding the preprocessor build it from code with `OciRun::builder()`, which has a method per option,
+//! e.g. `OciRun::builder().engine("podman").lang(LangConfig::rust()).strict(true).build()?`,
+//! failing like the config of a book on the options it can not use, e.g. an invalid ignore pattern.
+//!
+//! They can also run the directives and snippets elsewhere than in a container engine,
+//! e.g. in micro VMs or on a remote service, by implementing `backend::ExecutionBackend`:
+//!
+//! ```rust,ignore
+//! let preprocessor = OciRunConfig::load(root)?
+//!     .create_preprocessor(root.to_path_buf())?
+//!     .with_backend(MyBackend::new());
+//! ```
+//!
+//! Everything goes through the backend, the image digests of the metadata included, so the preprocessing
+//! of a chapter is unit tested without any container engine installed by giving it a fake backend:
+//!
+//! ```rust,ignore
+//! let content = OciRun::builder()
+//!     .lang(LangConfig::rust())
+//!     .backend(FakeBackend)
+//!     .build()?
+//!     .run_on_content(chapter, ".")?;
+//! ```
+//!
+//! `mock::MockEngine` is such a backend, answering canned outputs to the directives and snippets
+//! and failing on anything else, and keeping what it ran:
+//!
+//! ```rust,ignore
+//! let engine = MockEngine::new()
+//!     .directive("alpine", "cat VERSION", Ok("1.0\n"))
+//!     .snippet(&LangConfig::rust(), "fn main() { println!(\"hi\"); }", Ok("hi\n"));
+//! ```
+//!
+//! Backends return an `outcome::ExecutionResult`: an `ExecutionOutcome` with the stdout, stderr, exit code
+//! and duration of the execution, or an `ExecutionError` telling whether it failed, went over a limit
+//! or the engine could not be run or create its container. The outcomes are in the JSON report too.
+//!
+//! The executions of the reports, with their config, cache key, outcome and timing, are `Serialize` and `Deserialize`,
+//! and `Report::read_json` reads back those of a JSON report, to keep, diff or post-process them.
+//!
+//! The outputs of the snippets are kept in files under `~/.mdbook/ocirun/`, or the `cache_dir`, by default,
+//! another store, like a database or a remote service, is used by implementing `snippet::SnippetCache`
+//! and giving it to `OciSnippetRunner::cached_with`.
+//!
+//! To collect metrics, enforce policies or send notifications, an `hooks::ExecutionHook` given to `with_hook`
+//! is called before every execution, which it can refuse, and after it with its result.
+//!
+//! Tools preprocessing a `Book` they loaded themselves walk its chapters with `mdbook_ocirun::map_chapter`,
+//! running each from `chapter_working_dir(chapter)` like the preprocessor does:
+//!
+//! ```rust,ignore
+//! map_chapter(&mut book, &mut |chapter| {
+//!     let working_dir = preprocessor.chapter_working_dir(chapter);
+//!     chapter.content = preprocessor.run_on_content(&chapter.content, &working_dir)?;
+//!     Ok(())
+//! })?;
+//! ```
+//!
+//! Linters and editors find the directives of a chapter, with their image, command, attributes
+//! and position, by `mdbook_ocirun::parse_directives(content)`.
+//!
+pub mod allowlist;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod audit;
+pub mod backend;
+pub mod builder;
+pub mod directive;
+#[cfg(feature = "mdbook")]
+pub mod golden;
+pub mod hooks;
+pub mod mock;
 pub mod ocirun;
+pub mod outcome;
+mod progress;
+pub mod report;
 pub mod snippet;
+pub mod stats;
 mod utils;
 
+pub use directive::{parse_directives, Directive};
 pub use ocirun::OciRun;
 pub use ocirun::OciRunConfig;
+#[cfg(feature = "mdbook")]
+pub use utils::map_chapter;
@@ -1,19 +1,40 @@
-use clap::{Arg, ArgMatches, Command};
+use anyhow::bail;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use clap_complete::Shell;
 use mdbook::errors::Error;
 use mdbook::preprocess::CmdPreprocessor;
 use mdbook::preprocess::Preprocessor;
+use mdbook::MDBook;
 
+use std::env;
+use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
 use std::process;
 
-use mdbook_ocirun::OciRun;
+use mdbook_ocirun::allowlist::Allowlist;
+use mdbook_ocirun::ocirun::{LogLevel, Mode};
+use mdbook_ocirun::{OciRun, OciRunConfig};
 
 fn main() {
     let matches = make_app().get_matches();
 
-    if let Some(sub_args) = matches.subcommand_matches("supports") {
-        handle_supports(sub_args);
-    } else if let Err(e) = handle_preprocessing() {
+    let result = match matches.subcommand() {
+        Some(("supports", sub_args)) => handle_supports(sub_args),
+        Some(("run", sub_args)) => handle_run(sub_args),
+        Some(("check", sub_args)) => handle_check(sub_args),
+        Some(("warm", sub_args)) => handle_warm(sub_args),
+        Some(("list", sub_args)) => handle_list(sub_args),
+        Some(("approve", sub_args)) => handle_approve(sub_args),
+        Some(("audit", sub_args)) => handle_audit(sub_args),
+        Some(("stats", sub_args)) => handle_stats(sub_args),
+        Some(("golden", sub_args)) => handle_golden(sub_args),
+        Some(("inspect-config", sub_args)) => handle_inspect_config(sub_args),
+        Some(("completions", sub_args)) => handle_completions(sub_args),
+        // Without a subcommand it is called by mdbook as a preprocessor
+        _ => handle_preprocessing(),
+    };
+    if let Err(e) = result {
         eprintln!("{e}");
         process::exit(1);
     }
@@ -21,12 +42,132 @@ fn main() {
 
 fn make_app() -> Command {
     Command::new("mdbook-ocirun")
+        .version(env!("CARGO_PKG_VERSION"))
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .conflicts_with("verbose")
+                .help("Print nothing but errors"),
+        )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .global(true)
+                .action(ArgAction::Count)
+                .help("Print every command run, and the engine commands too with -vv"),
+        )
         .about("mdbook preprocessor to run arbitrary commands and replace the stdout of these commands inside the markdown file.")
         .subcommand(
             Command::new("supports")
                 .arg(Arg::new("renderer").required(true))
                 .about("Check whether a renderer is supported by this preprocessor"),
         )
+        .subcommand(
+            Command::new("run")
+                .arg(
+                    Arg::new("file")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Markdown file to process"),
+                )
+                .arg(
+                    Arg::new("working-dir")
+                        .long("working-dir")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Directory to run the commands from, the one of the file by default"),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the engine commands instead of running them"),
+                )
+                .about("Run the directives and snippets of a markdown file and print the result"),
+        )
+        .subcommand(
+            Command::new("check")
+                .arg(book_dir_arg())
+                .about("Run everything in the book without rendering it, failing if anything fails"),
+        )
+        .subcommand(
+            Command::new("warm")
+                .arg(book_dir_arg())
+                .about("Run the snippets of the book to cache their outputs for `mdbook serve`"),
+        )
+        .subcommand(
+            Command::new("list")
+                .arg(book_dir_arg())
+                .arg(json_arg())
+                .about("List every directive and snippet the book would run, without running them"),
+        )
+        .subcommand(
+            Command::new("approve")
+                .arg(book_dir_arg())
+                .about("Write every directive and snippet of the book to the allowlist of untrusted builds"),
+        )
+        .subcommand(
+            Command::new("audit")
+                .arg(book_dir_arg())
+                .arg(json_arg())
+                .about("Report the images, host mounts and commands used by the book"),
+        )
+        .subcommand(
+            Command::new("stats")
+                .arg(book_dir_arg())
+                .arg(json_arg())
+                .about("Count the code blocks of each chapter which are run and checked"),
+        )
+        .subcommand(
+            Command::new("golden")
+                .arg(book_dir_arg())
+                .arg(
+                    Arg::new("expected")
+                        .long("expected")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Directory of the expected chapters, at the same paths as in the book sources"),
+                )
+                .arg(
+                    Arg::new("update")
+                        .long("update")
+                        .action(ArgAction::SetTrue)
+                        .help("Write the preprocessed chapters as the expected ones"),
+                )
+                .about("Compare the preprocessed chapters of the book with the expected ones"),
+        )
+        .subcommand(
+            Command::new("inspect-config")
+                .arg(book_dir_arg())
+                .about("Print the effective config of the book, defaults included"),
+        )
+        .subcommand(
+            Command::new("completions")
+                .arg(
+                    Arg::new("shell")
+                        .required(true)
+                        .value_parser(clap::value_parser!(Shell))
+                        .help("Shell to complete the commands in"),
+                )
+                .about("Print the completion script of a shell"),
+        )
+}
+
+fn json_arg() -> Arg {
+    Arg::new("json")
+        .long("json")
+        .action(ArgAction::SetTrue)
+        .help("Print as JSON")
+}
+
+fn book_dir_arg() -> Arg {
+    Arg::new("dir")
+        .default_value(".")
+        .value_parser(clap::value_parser!(PathBuf))
+        .help("Root directory of the book")
 }
 
 fn handle_preprocessing() -> Result<(), Error> {
@@ -46,6 +187,215 @@ fn handle_preprocessing() -> Result<(), Error> {
     Ok(())
 }
 
+fn handle_run(sub_args: &ArgMatches) -> Result<(), Error> {
+    let file = sub_args
+        .get_one::<PathBuf>("file")
+        .expect("Required argument");
+    let working_dir = sub_args.get_one::<PathBuf>("working-dir");
+
+    // The config of the book in the current directory is used, if there is one
+    let root = Path::new(".");
+    let mut config = OciRunConfig::load(root)?;
+    if sub_args.get_flag("dry-run") {
+        config.mode = Mode::DryRun;
+    }
+    apply_log_level(sub_args, &mut config);
+    let preprocessor = config.create_preprocessor(root.to_path_buf())?;
+    let content = preprocessor.run_on_file(file, working_dir.map(PathBuf::as_path))?;
+    print!("{content}");
+    // Prints the summary, and writes the report, junit and log files of the config
+    preprocessor.finish_build()?;
+
+    Ok(())
+}
+
+fn load_book(sub_args: &ArgMatches) -> Result<(PathBuf, MDBook, OciRunConfig), Error> {
+    let dir = sub_args
+        .get_one::<PathBuf>("dir")
+        .expect("Argument with default value");
+    // Chapters are located from the book root, like when mdbook runs the preprocessor
+    env::set_current_dir(dir)?;
+    let root = env::current_dir()?;
+    let md = MDBook::load(&root)?;
+    let mut config = OciRunConfig::from_book(&md.config, &root)?;
+    apply_log_level(sub_args, &mut config);
+    Ok((root, md, config))
+}
+
+// The flags win over the `log_level` of the book
+fn apply_log_level(sub_args: &ArgMatches, config: &mut OciRunConfig) {
+    config.log_level = match (sub_args.get_flag("quiet"), sub_args.get_count("verbose")) {
+        (true, _) => LogLevel::Quiet,
+        (false, 0) => return,
+        (false, 1) => LogLevel::Verbose,
+        (false, _) => LogLevel::Debug,
+    };
+}
+
+// Exit codes of `check`, the ones of the failures are `FailureKind::exit_code`
+const CONFIG_ERROR: i32 = 3;
+const OUTPUT_ERROR: i32 = 7;
+
+// Errors come with their exit code, loading the book and writing its outputs fail differently
+fn check(sub_args: &ArgMatches) -> Result<(Vec<String>, i32), (Error, i32)> {
+    let (root, mut md, mut config) = load_book(sub_args).map_err(|e| (e, CONFIG_ERROR))?;
+    config.strict = true;
+    let preprocessor = config
+        .create_preprocessor(root)
+        .map_err(|e| (e, CONFIG_ERROR))?;
+    let failures = preprocessor.check_book(&mut md.book);
+    preprocessor.finish_build().map_err(|e| (e, OUTPUT_ERROR))?;
+    // Chapters failing before anything was run are execution failures too
+    let code = match preprocessor.report.exit_code() {
+        0 if !failures.is_empty() => 1,
+        code => code,
+    };
+    Ok((failures, code))
+}
+
+fn handle_check(sub_args: &ArgMatches) -> ! {
+    match check(sub_args) {
+        Ok((failures, _)) if failures.is_empty() => process::exit(0),
+        Ok((failures, code)) => {
+            eprintln!("{} failures:", failures.len());
+            for failure in failures {
+                eprintln!("  {failure}");
+            }
+            process::exit(code);
+        }
+        Err((e, code)) => {
+            eprintln!("{e}");
+            process::exit(code);
+        }
+    }
+}
+
+fn handle_warm(sub_args: &ArgMatches) -> Result<(), Error> {
+    let (root, mut md, config) = load_book(sub_args)?;
+    let preprocessor = config.create_preprocessor(root)?;
+    // Failed snippets are cached too, `check` is the one failing on them
+    let _ = preprocessor.check_book(&mut md.book);
+    preprocessor.finish_build()?;
+    Ok(())
+}
+
+fn handle_list(sub_args: &ArgMatches) -> Result<(), Error> {
+    let (root, md, config) = load_book(sub_args)?;
+    let entries = config.create_preprocessor(root)?.list_book(&md.book);
+    if sub_args.get_flag("json") {
+        serde_json::to_writer_pretty(io::stdout(), &entries)?;
+        println!();
+    } else {
+        for entry in entries {
+            println!("{entry}");
+        }
+    }
+    Ok(())
+}
+
+fn handle_approve(sub_args: &ArgMatches) -> Result<(), Error> {
+    let (root, md, config) = load_book(sub_args)?;
+    let Some(allowlist) = &config.allowlist else {
+        bail!("No allowlist to write, set `allowlist` in [preprocessor.ocirun]");
+    };
+    let entries = config
+        .create_preprocessor(root.clone())?
+        .list_book(&md.book);
+    fs::write(root.join(allowlist), Allowlist::render(&entries))?;
+    eprintln!(
+        "{} executions approved in {}",
+        entries.len(),
+        allowlist.display()
+    );
+    Ok(())
+}
+
+fn handle_audit(sub_args: &ArgMatches) -> Result<(), Error> {
+    let (root, md, config) = load_book(sub_args)?;
+    let audit = config.create_preprocessor(root)?.audit_book(&md.book);
+    if sub_args.get_flag("json") {
+        serde_json::to_writer_pretty(io::stdout(), &audit)?;
+        println!();
+    } else {
+        print!("{audit}");
+    }
+    Ok(())
+}
+
+fn handle_stats(sub_args: &ArgMatches) -> Result<(), Error> {
+    let (root, md, config) = load_book(sub_args)?;
+    let stats = config.create_preprocessor(root)?.stats_book(&md.book);
+    if sub_args.get_flag("json") {
+        serde_json::to_writer_pretty(io::stdout(), &stats)?;
+        println!();
+    } else {
+        print!("{stats}");
+    }
+    Ok(())
+}
+
+fn handle_golden(sub_args: &ArgMatches) -> Result<(), Error> {
+    // Relative to where the command is run, not to the book
+    let expected = env::current_dir()?.join(
+        sub_args
+            .get_one::<PathBuf>("expected")
+            .expect("Required argument"),
+    );
+    let (root, mut md, config) = load_book(sub_args)?;
+    let preprocessor = config.create_preprocessor(root)?;
+    let diffs = preprocessor.golden_book(&mut md.book, &expected, sub_args.get_flag("update"))?;
+    preprocessor.finish_build()?;
+    if diffs.is_empty() {
+        return Ok(());
+    }
+    for diff in &diffs {
+        eprintln!("{diff}");
+    }
+    bail!("{} differences with {}", diffs.len(), expected.display())
+}
+
+fn handle_inspect_config(sub_args: &ArgMatches) -> Result<(), Error> {
+    let (_, _, config) = load_book(sub_args)?;
+    let resolved = config.resolved();
+    println!("# Effective config of the book");
+    let defaulted = config.defaulted();
+    if !defaulted.is_empty() {
+        println!(
+            "# Not set in book.toml, so using defaults: {}",
+            defaulted.join(", ")
+        );
+    }
+    println!("# Snippets are run for these languages:");
+    for lang in &resolved.langs {
+        println!(
+            "#   {} in image `{}`: {}",
+            lang.name,
+            lang.image,
+            lang.command.join(" ")
+        );
+    }
+    println!(
+        "# Directives run with `{}`, in `alpine` unless they give an image",
+        resolved.engine.as_deref().unwrap_or_default()
+    );
+    let mut ocirun = toml::map::Map::new();
+    ocirun.insert("ocirun".into(), toml::Value::try_from(&resolved)?);
+    let mut preprocessor = toml::map::Map::new();
+    preprocessor.insert("preprocessor".into(), ocirun.into());
+    print!("\n{}", toml::to_string(&preprocessor)?);
+    Ok(())
+}
+
+fn handle_completions(sub_args: &ArgMatches) -> Result<(), Error> {
+    let shell = *sub_args
+        .get_one::<Shell>("shell")
+        .expect("Required argument");
+    let mut app = make_app();
+    let name = app.get_name().to_string();
+    clap_complete::generate(shell, &mut app, name, &mut io::stdout());
+    Ok(())
+}
+
 fn handle_supports(sub_args: &ArgMatches) -> ! {
     let renderer = sub_args
         .get_one::<String>("renderer")
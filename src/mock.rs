@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::backend::{DirectiveRun, ExecutionBackend};
+use crate::ocirun::LangConfig;
+use crate::outcome::{ExecutionError, ExecutionOutcome, ExecutionResult};
+use crate::report::command_hash;
+use crate::snippet::{CodeSnippet, SnippetRunner};
+
+/// A backend answering canned outputs instead of running anything, for deterministic tests.
+///
+/// The outputs are keyed by the hash of the image and command of the directives,
+/// and of the image, command and source of the snippets, all trimmed.
+/// Anything without an output fails, telling what was run.
+/// Given in an `Arc`, what it ran is still available once the build is done.
+#[derive(Default)]
+pub struct MockEngine {
+    outputs: HashMap<String, ExecutionResult>,
+    cached: Vec<String>,
+    runs: Mutex<Vec<String>>,
+}
+
+fn directive_key(image: &str, command: &str) -> String {
+    command_hash(image, command.trim())
+}
+
+fn snippet_key(image: &str, command: &[String], source: &str) -> String {
+    command_hash(image, &format!("{}:{}", command.join(" "), source.trim()))
+}
+
+// Printed on stdout when it is `Ok`, on stderr with the exit code 1 otherwise
+fn result(output: Result<&str, &str>) -> ExecutionResult {
+    match output {
+        Ok(stdout) => Ok(ExecutionOutcome::success(stdout)),
+        Err(stderr) => Err(ExecutionError::Failed(ExecutionOutcome::failure(stderr, 1))),
+    }
+}
+
+impl MockEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers `output` to the directive running `command` in `image`,
+    /// on stdout when it is `Ok`, on stderr with the exit code 1 otherwise.
+    pub fn directive(mut self, image: &str, command: &str, output: Result<&str, &str>) -> Self {
+        self.outputs
+            .insert(directive_key(image, command), result(output));
+        self
+    }
+
+    /// Answers `output` to the snippet of the language with this source.
+    pub fn snippet(mut self, lang: &LangConfig, source: &str, output: Result<&str, &str>) -> Self {
+        self.outputs.insert(
+            snippet_key(&lang.image, &lang.command, source),
+            result(output),
+        );
+        self
+    }
+
+    /// Tells that the output of the snippet is cached, like after a previous build.
+    pub fn cached_snippet(mut self, lang: &LangConfig, source: &str) -> Self {
+        self.cached
+            .push(snippet_key(&lang.image, &lang.command, source));
+        self
+    }
+
+    /// Hashes of everything run so far, in order.
+    pub fn runs(&self) -> Vec<String> {
+        self.runs.lock().unwrap().clone()
+    }
+
+    fn answer(&self, key: String, what: &str) -> ExecutionResult {
+        self.runs.lock().unwrap().push(key.clone());
+        self.outputs
+            .get(&key)
+            .cloned()
+            .unwrap_or_else(|| result(Err(&format!("no mock output for {what}\n"))))
+    }
+}
+
+impl ExecutionBackend for MockEngine {
+    fn run_directive(&self, directive: &DirectiveRun) -> ExecutionResult {
+        let key = directive_key(directive.image, directive.command);
+        let what = format!(
+            "`{}` in image `{}`",
+            directive.command.trim(),
+            directive.image
+        );
+        self.answer(key, &what)
+    }
+
+    fn run_snippet(&self, snippet: &CodeSnippet) -> ExecutionResult {
+        SnippetRunner::run(self, snippet)
+    }
+
+    fn is_cached(&self, snippet: &CodeSnippet) -> bool {
+        SnippetRunner::is_cached(self, snippet)
+    }
+
+    fn image_digest(&self, image: &str) -> Option<String> {
+        Some(format!("{image}@sha256:mock"))
+    }
+}
+
+impl SnippetRunner for MockEngine {
+    fn run(&self, snippet: &CodeSnippet) -> ExecutionResult {
+        let source = snippet.source.get_content();
+        let key = snippet_key(&snippet.config.image, &snippet.config.command, &source);
+        self.answer(key, &format!("snippet in image `{}`", snippet.config.image))
+    }
+
+    fn is_cached(&self, snippet: &CodeSnippet) -> bool {
+        let source = snippet.source.get_content();
+        self.cached.contains(&snippet_key(
+            &snippet.config.image,
+            &snippet.config.command,
+            &source,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{directive_key, MockEngine};
+    use crate::ocirun::LangConfig;
+    use crate::report::CacheStatus;
+    use crate::OciRun;
+
+    #[test]
+    pub fn test_mock_engine() {
+        let rust = LangConfig::rust();
+        let engine = MockEngine::new()
+            .directive("alpine", "cat VERSION", Ok("1.0\n"))
+            .directive("alpine", "ls missing", Err("ls: missing: No such file\n"))
+            .snippet(&rust, "fn main() { println!(\"hi\"); }", Ok("hi\n"))
+            .cached_snippet(&rust, "fn main() { println!(\"hi\"); }");
+        let engine = Arc::new(engine);
+        let preprocessor = OciRun::builder()
+            .lang(rust)
+            .backend(engine.clone())
+            .build()
+            .unwrap();
+        let content = r#"Version <!-- ocirun alpine cat VERSION -->.
+<!-- ocirun alpine ls missing -->
+<!-- ocirun alpine date -->
+```rust,ocirun
+fn main() { println!("hi"); }
+```
+```console,expected
+bye
+```
+"#;
+        assert_eq!(
+            preprocessor.run_on_content(content, ".").unwrap(),
+            r#"Version 1.0.
+```rust,ocirun
+fn main() { println!("hi"); }
+```
+```console,success
+hi
+```
+```console,expected
+bye
+```
+"#
+        );
+        let executions = preprocessor.report.executions();
+        assert_eq!(executions.len(), 4);
+        assert!(executions[0].success);
+        assert_eq!(executions[1].exit_code, Some(1));
+        assert_eq!(
+            executions[1].error.as_deref(),
+            Some("ls: missing: No such file\n")
+        );
+        assert_eq!(
+            executions[2].error.as_deref(),
+            Some("no mock output for `date` in image `alpine`\n")
+        );
+        assert!(!executions[3].success);
+        assert_eq!(executions[3].cache, CacheStatus::Hit);
+        let runs = engine.runs();
+        assert_eq!(runs.len(), 4);
+        assert_eq!(runs[0], directive_key("alpine", "cat VERSION"));
+        assert!(executions[3]
+            .error
+            .as_deref()
+            .unwrap()
+            .starts_with("output differs from the expected one"));
+    }
+}
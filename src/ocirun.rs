@@ -1,30 +1,106 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
+use std::io::{self, IsTerminal};
+use std::ops::Range;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
 use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use lazy_static::lazy_static;
-use regex::Captures;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "mdbook")]
 use mdbook::book::Book;
+#[cfg(feature = "mdbook")]
+use mdbook::book::BookItem;
+#[cfg(feature = "mdbook")]
 use mdbook::book::Chapter;
+#[cfg(feature = "mdbook")]
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+#[cfg(feature = "mdbook")]
+use mdbook::Config;
 
+use crate::allowlist::directive_digest;
+use crate::allowlist::snippet_digest;
+use crate::allowlist::Allowlist;
+use crate::allowlist::ALLOWLIST_ENV;
+use crate::allowlist::UNTRUSTED_ENV;
+use crate::backend::DirectiveRun;
+use crate::backend::ExecutionBackend;
+use crate::backend::OciBackend;
+use crate::directive::find_directives;
+use crate::directive::Directive;
+use crate::directive::DirectiveRef;
+use crate::hooks::ExecutionHook;
+use crate::outcome::ExecutionError;
+use crate::outcome::ExecutionOutcome;
+use crate::outcome::ExecutionResult;
+use crate::progress::Progress;
+use crate::report::CacheStatus;
+use crate::report::Execution;
+use crate::report::ExecutionKind;
+use crate::report::Report;
+use crate::snippet::CodeSnippetCache;
 use crate::snippet::OciSnippetRunner;
-use crate::snippet::SnippetRunner;
+use crate::snippet::SnippetRef;
+use crate::snippet::Snippets;
+use crate::utils::capture::DEFAULT_MAX_OUTPUT_BYTES;
 use crate::utils::format_whitespace;
+#[cfg(feature = "mdbook")]
 use crate::utils::map_chapter;
+use crate::utils::sanitize;
+use crate::utils::CaptureLimits;
+use crate::utils::IgnoredChapters;
+use crate::utils::Redactor;
+use crate::utils::Secrets;
+
+/// What a failure of a snippet means for the build.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Error,
+    /// Only a warning, even in strict mode, for experimental languages
+    Warn,
+}
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct LangConfig {
     pub name: String,
     pub image: String,
     pub command: Vec<String>,
+    #[serde(default)]
+    pub severity: Severity,
+    /// Network of the snippets, `default_network` when unset
+    #[serde(default)]
+    pub network: Option<String>,
+    /// Limits of the snippets, over the global ones
+    #[serde(default)]
+    pub limits: ResourceLimits,
+}
+
+fn default_shell() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ImageConfig {
+    pub name: String,
+    #[serde(default = "default_shell")]
+    pub shell: bool,
 }
 
 impl LangConfig {
@@ -37,29 +113,600 @@ impl LangConfig {
                 "-ec".into(),
                 "rustc source -o binary && ./binary < input".into(),
             ],
+            severity: Severity::Error,
+            network: None,
+            limits: ResourceLimits::default(),
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, Default, PartialEq)]
+const CONFIG_KEY: &str = "preprocessor.ocirun";
+
+// Name of the renderer given by `mdbook test`
+#[cfg(feature = "mdbook")]
+const TEST_RENDERER: &str = "test";
+
+// Only set by `mdbook serve`
+#[cfg(feature = "mdbook")]
+const LIVE_RELOAD_KEY: &str = "output.html.live-reload-endpoint";
+
+pub(crate) const NOT_EXECUTED: &str = "not yet executed, run `mdbook-ocirun warm`";
+
+// Set to `1` where there is no container engine, to render the book anyway
+const SKIP_ENV: &str = "OCIRUN_SKIP";
+
+pub(crate) const SKIPPED: &str = "not executed, OCIRUN_SKIP is set";
+
+// Whether a flag is set in the environment
+fn is_set(name: &str) -> bool {
+    std::env::var(name).is_ok_and(|value| value == "1" || value == "true")
+}
+
+const DEFAULT_ENGINE: &str = "docker";
+
+const DEFAULT_MAX_RECURSION_DEPTH: usize = 3;
+
+// Containers get no network unless they ask for it
+const DEFAULT_NETWORK: &str = "none";
+
+// Files written in the chapters belong to the user building the book, where users have ids
+const HOST_USER: &str = "host";
+const IMAGE_USER: &str = "image";
+const DEFAULT_USER: &str = if cfg!(unix) { HOST_USER } else { IMAGE_USER };
+
+const REDACT_PATTERN_PREFIX: &str = "re:";
+
+/// Something to mask in the outputs: the value of an environment variable,
+/// or every match of a regex when prefixed with `re:`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub enum RedactRule {
+    Env(String),
+    Pattern(Regex),
+}
+
+impl FromStr for RedactRule {
+    type Err = regex::Error;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        match rule.strip_prefix(REDACT_PATTERN_PREFIX) {
+            Some(pattern) => Regex::new(pattern).map(Self::Pattern),
+            None => Ok(Self::Env(rule.to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for RedactRule {
+    type Error = regex::Error;
+
+    fn try_from(rule: String) -> Result<Self, Self::Error> {
+        rule.parse()
+    }
+}
+
+impl From<RedactRule> for String {
+    fn from(rule: RedactRule) -> Self {
+        match rule {
+            RedactRule::Env(name) => name,
+            RedactRule::Pattern(pattern) => format!("{REDACT_PATTERN_PREFIX}{pattern}"),
+        }
+    }
+}
+
+impl PartialEq for RedactRule {
+    fn eq(&self, other: &Self) -> bool {
+        String::from(self.clone()) == String::from(other.clone())
+    }
+}
+
+/// Resources a container can use, unlimited when unset.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct ResourceLimits {
+    /// Like `512m` or `2g`
+    #[serde(default)]
+    pub memory: Option<String>,
+    #[serde(default)]
+    pub cpus: Option<f64>,
+    /// How many processes, to stop fork bombs
+    #[serde(default)]
+    pub pids: Option<u32>,
+}
+
+impl ResourceLimits {
+    /// These limits, with the ones set by `other` instead.
+    pub fn overridden_by(&self, other: &ResourceLimits) -> Self {
+        Self {
+            memory: other.memory.clone().or(self.memory.clone()),
+            cpus: other.cpus.or(self.cpus),
+            pids: other.pids.or(self.pids),
+        }
+    }
+
+    // The limits set by the `memory=`, `cpus=` and `pids=` attributes of a directive or snippet
+    pub(crate) fn from_attributes<'a>(attribute: impl Fn(&str) -> Option<&'a str>) -> Result<Self> {
+        let parse = |name: &str| -> Result<Option<&'a str>> {
+            match attribute(name) {
+                Some("") => bail!("Empty value for ocirun attribute `{name}`"),
+                value => Ok(value),
+            }
+        };
+        let invalid = |name: &str, value: &str| {
+            format!("Invalid value `{value}` for ocirun attribute `{name}`")
+        };
+        Ok(Self {
+            memory: parse("memory")?.map(String::from),
+            cpus: parse("cpus")?
+                .map(|value| value.parse().with_context(|| invalid("cpus", value)))
+                .transpose()?,
+            pids: parse("pids")?
+                .map(|value| value.parse().with_context(|| invalid("pids", value)))
+                .transpose()?,
+        })
+    }
+
+    // Arguments of `run` and `create` enforcing the limits
+    pub(crate) fn engine_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(memory) = &self.memory {
+            args.extend(["--memory".into(), memory.clone()]);
+        }
+        if let Some(cpus) = self.cpus {
+            args.extend(["--cpus".into(), cpus.to_string()]);
+        }
+        if let Some(pids) = self.pids {
+            args.extend(["--pids-limit".into(), pids.to_string()]);
+        }
+        args
+    }
+}
+
+/// Hardening of every container, for books running code they do not fully trust.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct SecurityOptions {
+    /// Capabilities to drop, like `ALL`
+    #[serde(default)]
+    pub cap_drop: Vec<String>,
+    /// Seccomp profile, relative to the book root
+    #[serde(default)]
+    pub seccomp: Option<PathBuf>,
+    /// Whether the processes can not gain privileges, e.g. through setuid binaries
+    #[serde(default)]
+    pub no_new_privileges: bool,
+}
+
+impl SecurityOptions {
+    // Arguments of `run` and `create` applying the options
+    pub(crate) fn engine_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        for capability in &self.cap_drop {
+            args.extend(["--cap-drop".into(), capability.clone()]);
+        }
+        if let Some(profile) = &self.seccomp {
+            args.extend([
+                "--security-opt".into(),
+                format!("seccomp={}", profile.display()),
+            ]);
+        }
+        if self.no_new_privileges {
+            args.extend(["--security-opt".into(), "no-new-privileges".into()]);
+        }
+        args
+    }
+}
+
+/// Where the value of a secret is read from on the host.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretSource {
+    /// An environment variable, given to the containers under the name of the secret
+    Env(String),
+    /// A file relative to the book root, mounted read-only at `/run/secrets/<name>`
+    File(PathBuf),
+}
+
+/// What a directive sees of the host: the directory mounted into its container, which it runs from.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MountScope {
+    /// The directory of its chapter
+    #[default]
+    Chapter,
+    /// The sources of the book
+    Src,
+    /// The root of the book, e.g. to run the scripts of a shared `tools/` directory
+    Root,
+}
+
+impl FromStr for MountScope {
+    type Err = anyhow::Error;
+
+    fn from_str(scope: &str) -> Result<Self> {
+        match scope {
+            "chapter" => Ok(Self::Chapter),
+            "src" => Ok(Self::Src),
+            "root" => Ok(Self::Root),
+            _ => bail!(
+                "Invalid value `{scope}` for ocirun attribute `mount`, expected chapter, src or root"
+            ),
+        }
+    }
+}
+
+/// How the trailing line breaks of a block output are rendered,
+/// inline outputs never keep them.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TrailingNewlines {
+    /// Exactly one line break
+    Single,
+    /// As printed by the command
+    #[default]
+    Preserve,
+    /// No line break at all
+    Strip,
+}
+
+/// Whether the containers are actually run.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    #[default]
+    Run,
+    /// Print the engine invocations instead, and render placeholders for their outputs
+    DryRun,
+}
+
+/// Which outputs end up in the book.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Render {
+    #[default]
+    All,
+    /// Only the failed directives and snippets, the successful ones are removed with their output
+    FailuresOnly,
+}
+
+/// How the book is built by `mdbook serve`.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServeMode {
+    #[default]
+    Run,
+    /// Only cached results, placeholders for everything else
+    CacheOnly,
+}
+
+/// What the preprocessor prints on stderr, each level printing the messages of the previous ones.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    /// Nothing but errors
+    Quiet,
+    /// Failures, dry-run commands and the summary of the build
+    #[default]
+    Normal,
+    /// Every directive and snippet run
+    Verbose,
+    /// The engine commands too
+    Debug,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone, PartialEq)]
 pub struct OciRunConfig {
     #[serde(default)]
     pub engine: Option<String>,
     #[serde(default)]
     pub langs: Vec<LangConfig>,
+    #[serde(default)]
+    pub images: Vec<ImageConfig>,
+    #[serde(default)]
+    pub max_output_bytes: Option<usize>,
+    #[serde(default)]
+    pub max_output_lines: Option<usize>,
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    #[serde(default)]
+    pub max_recursion_depth: Option<usize>,
+    #[serde(default)]
+    pub trailing_newlines: TrailingNewlines,
+    #[serde(default)]
+    pub strip_bom: Option<bool>,
+    #[serde(default)]
+    pub strip_invisible: Option<bool>,
+    #[serde(default)]
+    pub redact: Vec<RedactRule>,
+    #[serde(default)]
+    pub report: Option<PathBuf>,
+    #[serde(default)]
+    pub junit: Option<PathBuf>,
+    #[serde(default)]
+    pub strict: bool,
+    #[serde(default)]
+    pub mode: Mode,
+    #[serde(default)]
+    pub log_level: LogLevel,
+    #[serde(default)]
+    pub stream_output: bool,
+    #[serde(default)]
+    pub metadata: bool,
+    #[serde(default)]
+    pub serve_mode: ServeMode,
+    #[serde(default)]
+    pub render: Render,
+    #[serde(default)]
+    pub log_file: Option<PathBuf>,
+    #[serde(default)]
+    pub default_network: Option<String>,
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    #[serde(default)]
+    pub security: SecurityOptions,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub runtime: Option<String>,
+    #[serde(default)]
+    pub secrets: BTreeMap<String, SecretSource>,
+    #[serde(default)]
+    pub untrusted: bool,
+    #[serde(default)]
+    pub allowlist: Option<PathBuf>,
+    #[serde(default)]
+    pub source_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub workspace: Option<PathBuf>,
+    #[serde(default)]
+    pub mount_scope: MountScope,
+    #[serde(default)]
+    pub ignore: Vec<String>,
 }
 
 impl OciRunConfig {
-    pub fn create_preprocessor(&self, root_path: PathBuf) -> OciRun {
-        let engine = match &self.engine {
-            Some(engine) => engine.clone(),
-            None => "docker".to_string(),
+    #[cfg(feature = "mdbook")]
+    pub fn from_book_config(config: &Config) -> Result<Self> {
+        Ok(config
+            .get_deserialized_opt::<OciRunConfig, _>(CONFIG_KEY)
+            .with_context(|| format!("Could not deserialize [{CONFIG_KEY}]"))?
+            .unwrap_or_default())
+    }
+
+    /// Like [`OciRunConfig::from_book_config`], with the config of the workspace of the book at `root`.
+    #[cfg(feature = "mdbook")]
+    pub fn from_book(config: &Config, root: &Path) -> Result<Self> {
+        let table = config
+            .get_deserialized_opt::<toml::Table, _>(CONFIG_KEY)
+            .with_context(|| format!("Could not deserialize [{CONFIG_KEY}]"))?
+            .unwrap_or_default();
+        Self::from_table(table, root)
+    }
+
+    // Reads the `book.toml` of the book at `root`, if there is one
+    #[cfg(feature = "mdbook")]
+    pub fn load(root: &Path) -> Result<Self> {
+        let path = root.join("book.toml");
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Self::from_book(&Config::from_disk(path)?, root)
+    }
+
+    // Without mdbook, only the `[preprocessor.ocirun]` table is read
+    #[cfg(not(feature = "mdbook"))]
+    pub fn load(root: &Path) -> Result<Self> {
+        let path = root.join("book.toml");
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let book: toml::Value = toml::from_str(&content)
+            .with_context(|| format!("Could not parse {}", path.display()))?;
+        let Some(config) = CONFIG_KEY
+            .split('.')
+            .try_fold(&book, |value, key| value.get(key))
+        else {
+            return Ok(Self::default());
+        };
+        let table = config
+            .as_table()
+            .cloned()
+            .with_context(|| format!("Could not deserialize [{CONFIG_KEY}]"))?;
+        Self::from_table(table, root)
+    }
+
+    // The options of the workspace are the defaults of the ones of the book
+    fn from_table(mut table: toml::Table, root: &Path) -> Result<Self> {
+        if let Some(workspace) = table.get("workspace").and_then(toml::Value::as_str) {
+            let workspace = PathBuf::from(workspace);
+            for (key, value) in Self::read_workspace(&workspace, root)? {
+                table.entry(key).or_insert(value);
+            }
+        }
+        let config: Self = toml::Value::Table(table)
+            .try_into()
+            .with_context(|| format!("Could not deserialize [{CONFIG_KEY}]"))?;
+        // A broken pattern would run the chapters meant to be skipped
+        IgnoredChapters::new(&config.ignore, root)?;
+        Ok(config)
+    }
+
+    // The paths of the workspace config are relative to it, they are made relative to the book root
+    fn read_workspace(path: &Path, root: &Path) -> Result<toml::Table> {
+        let content = fs::read_to_string(root.join(path))
+            .with_context(|| format!("Could not read the workspace {}", path.display()))?;
+        let config: OciRunConfig = toml::from_str(&content)
+            .with_context(|| format!("Could not parse the workspace {}", path.display()))?;
+        let config = config.rebased(path.parent().unwrap_or(Path::new("")));
+        let mut table = toml::Table::try_from(config)?;
+        // A workspace has no workspace
+        table.remove("workspace");
+        Ok(table)
+    }
+
+    fn rebased(self, dir: &Path) -> Self {
+        let rebase = |path: Option<PathBuf>| path.map(|path| dir.join(path));
+        Self {
+            report: rebase(self.report),
+            junit: rebase(self.junit),
+            log_file: rebase(self.log_file),
+            allowlist: rebase(self.allowlist),
+            source_dir: rebase(self.source_dir),
+            cache_dir: rebase(self.cache_dir),
+            security: SecurityOptions {
+                seccomp: rebase(self.security.seccomp),
+                ..self.security
+            },
+            secrets: self
+                .secrets
+                .into_iter()
+                .map(|(name, source)| match source {
+                    SecretSource::File(path) => (name, SecretSource::File(dir.join(path))),
+                    source => (name, source),
+                })
+                .collect(),
+            ..self
+        }
+    }
+
+    /// The config with the defaults of every unset option filled in.
+    pub fn resolved(&self) -> Self {
+        Self {
+            engine: self.engine.clone().or(Some(DEFAULT_ENGINE.into())),
+            max_output_bytes: self.max_output_bytes.or(Some(DEFAULT_MAX_OUTPUT_BYTES)),
+            max_recursion_depth: self
+                .max_recursion_depth
+                .or(Some(DEFAULT_MAX_RECURSION_DEPTH)),
+            strip_bom: self.strip_bom.or(Some(true)),
+            strip_invisible: self.strip_invisible.or(Some(false)),
+            default_network: self
+                .default_network
+                .clone()
+                .or(Some(DEFAULT_NETWORK.into())),
+            user: self.user.clone().or(Some(DEFAULT_USER.into())),
+            ..self.clone()
+        }
+    }
+
+    // Names of the options left to their default value
+    pub fn defaulted(&self) -> Vec<&'static str> {
+        [
+            ("engine", self.engine.is_none()),
+            ("max_output_bytes", self.max_output_bytes.is_none()),
+            ("max_recursion_depth", self.max_recursion_depth.is_none()),
+            ("strip_bom", self.strip_bom.is_none()),
+            ("strip_invisible", self.strip_invisible.is_none()),
+            ("default_network", self.default_network.is_none()),
+            ("user", self.user.is_none()),
+        ]
+        .into_iter()
+        .filter_map(|(name, defaulted)| defaulted.then_some(name))
+        .collect()
+    }
+
+    /// The preprocessor of the book at `root_path`, failing on ignore patterns it can not match,
+    /// or on an allowlist it can not read for an untrusted build.
+    pub fn create_preprocessor(&self, root_path: PathBuf) -> Result<OciRun> {
+        // A broken pattern would run the chapters meant to be skipped
+        let ignored = IgnoredChapters::new(&self.ignore, &root_path)?;
+        // Without one nothing is approved, but a typo in its path must not look like that
+        let allowlist = match self.untrusted || is_set(UNTRUSTED_ENV) {
+            true => std::env::var_os(ALLOWLIST_ENV)
+                .map(PathBuf::from)
+                .or(self.allowlist.as_ref().map(|path| root_path.join(path)))
+                .map(|path| Allowlist::read(&path))
+                .transpose()?
+                .unwrap_or_default(),
+            false => Allowlist::default(),
+        };
+        Ok(self.preprocessor(root_path, ignored, allowlist))
+    }
+
+    fn preprocessor(
+        &self,
+        root_path: PathBuf,
+        ignored: IgnoredChapters,
+        allowlist: Allowlist,
+    ) -> OciRun {
+        let config = self.resolved();
+        let engine = config.engine.unwrap();
+        let capture_limits = CaptureLimits {
+            max_bytes: config.max_output_bytes.unwrap(),
+            max_lines: self.max_output_lines,
+            timeout: self.timeout.map(Duration::from_secs),
+        };
+        let secrets = Secrets::new(&self.secrets, &root_path);
+        // Shared by the books of a workspace setting the same one
+        let cache = match &self.cache_dir {
+            Some(path) => CodeSnippetCache::new(root_path.join(path).display().to_string()),
+            None => CodeSnippetCache::default(),
+        };
+        let redactor = Redactor::new(&self.redact).with_values(secrets.values());
+        let report_path = self.report.as_ref().map(|path| root_path.join(path));
+        let junit_path = self.junit.as_ref().map(|path| root_path.join(path));
+        let log_path = self.log_file.as_ref().map(|path| root_path.join(path));
+        let security = SecurityOptions {
+            seccomp: self
+                .security
+                .seccomp
+                .as_ref()
+                .map(|path| root_path.join(path)),
+            ..self.security.clone()
         };
         OciRun {
             engine: engine.clone(),
+            src_dir: book_src_dir(&root_path),
             root_path,
             langs: self.langs.clone(),
-            snippet_runner: Box::new(OciSnippetRunner::new(engine).cached()),
+            images: self.images.clone(),
+            capture_limits: capture_limits.clone(),
+            max_recursion_depth: config.max_recursion_depth.unwrap(),
+            trailing_newlines: self.trailing_newlines,
+            strip_bom: config.strip_bom.unwrap(),
+            strip_invisible: config.strip_invisible.unwrap(),
+            default_network: config.default_network.unwrap(),
+            resource_limits: self.limits.clone(),
+            security,
+            user: config.user.unwrap(),
+            runtime: self.runtime.clone(),
+            secrets: secrets.clone(),
+            untrusted: self.untrusted || is_set(UNTRUSTED_ENV),
+            allowlist,
+            source_dir: self.source_dir.clone(),
+            mount_scope: self.mount_scope,
+            ignored,
+            redactor: redactor.clone(),
+            progress: Progress::default(),
+            report: Report::default(),
+            report_path,
+            junit_path,
+            log_path,
+            strict: self.strict,
+            mode: self.mode,
+            log_level: self.log_level,
+            stream_output: self.stream_output,
+            metadata: self.metadata,
+            render: self.render,
+            cache_only: false,
+            skip: is_set(SKIP_ENV),
+            digests: Mutex::default(),
+            hooks: vec![],
+            backend: Box::new(
+                OciBackend::new(
+                    engine.clone(),
+                    Box::new(
+                        OciSnippetRunner::new(engine)
+                            .with_limits(capture_limits)
+                            .with_redactor(redactor)
+                            .with_secrets(secrets.clone())
+                            .with_log_level(self.log_level)
+                            .with_stream_output(self.stream_output)
+                            .cached_with(cache),
+                    ),
+                )
+                .with_secrets(secrets),
+            ),
         }
     }
 }
@@ -68,54 +715,284 @@ pub struct OciRun {
     pub engine: String,
     pub root_path: PathBuf,
     pub langs: Vec<LangConfig>,
-    pub snippet_runner: Box<dyn SnippetRunner>,
+    pub images: Vec<ImageConfig>,
+    pub capture_limits: CaptureLimits,
+    pub max_recursion_depth: usize,
+    pub trailing_newlines: TrailingNewlines,
+    pub strip_bom: bool,
+    pub strip_invisible: bool,
+    /// Network of the containers which do not ask for one
+    pub default_network: String,
+    /// Resources of every container, see [`ResourceLimits`]
+    pub resource_limits: ResourceLimits,
+    /// Hardening of every container, see [`SecurityOptions`]
+    pub security: SecurityOptions,
+    /// User of the directive containers: `host`, `image` or one given to `--user`
+    pub user: String,
+    /// OCI runtime of every container, like `runsc` for gVisor, the default one of the engine when unset
+    pub runtime: Option<String>,
+    /// Given to every container, masked in every output
+    pub secrets: Secrets,
+    /// Whether only the executions of the allowlist are run
+    pub untrusted: bool,
+    pub allowlist: Allowlist,
+    /// Sources the chapters run from when they have their directory there, relative to the book root,
+    /// e.g. the ones of the original language for translations built from their own sources
+    pub source_dir: Option<PathBuf>,
+    /// Sources of the book, relative to its root
+    pub src_dir: PathBuf,
+    /// What the directives see of the host, unless they set the `mount` attribute
+    pub mount_scope: MountScope,
+    /// Chapters passed through untouched, from `ignore` and `.ocirunignore`
+    pub ignored: IgnoredChapters,
+    pub redactor: Redactor,
+    pub(crate) progress: Progress,
+    pub report: Report,
+    pub report_path: Option<PathBuf>,
+    pub junit_path: Option<PathBuf>,
+    pub log_path: Option<PathBuf>,
+    pub strict: bool,
+    pub mode: Mode,
+    pub log_level: LogLevel,
+    pub stream_output: bool,
+    pub metadata: bool,
+    pub render: Render,
+    /// Whether only cached results are rendered, see [`ServeMode::CacheOnly`]
+    pub cache_only: bool,
+    /// Whether nothing is run at all, placeholders are rendered instead
+    pub skip: bool,
+    // Digests of the images already resolved for the metadata
+    digests: Mutex<HashMap<String, Option<String>>>,
+    /// Where everything is run, see [`OciRun::with_backend`]
+    pub backend: Box<dyn ExecutionBackend>,
+    /// Called around every execution, see [`OciRun::with_hook`]
+    pub hooks: Vec<Box<dyn ExecutionHook>>,
 }
 
+// Ignores and approves nothing, whatever the files of the current directory, as the
+// preprocessor of a book is created from its own config, see `Preprocessor::run`
 impl Default for OciRun {
     fn default() -> Self {
-        OciRunConfig::default().create_preprocessor(Path::new(".").to_path_buf())
+        OciRunConfig::default().preprocessor(
+            Path::new(".").to_path_buf(),
+            IgnoredChapters::default(),
+            Allowlist::default(),
+        )
     }
 }
 
-lazy_static! {
-    static ref OCIRUN_REG_NEWLINE: Regex = Regex::new(r"<!--[ ]*ocirun (.*?)-->\r?\n")
-        .expect("Failed to init regex for finding newline pattern");
-    static ref OCIRUN_REG_INLINE: Regex = Regex::new(r"<!--[ ]*ocirun (.*?)-->")
-        .expect("Failed to init regex for finding inline pattern");
+// A directive once everything which could keep it from running was checked
+pub(crate) enum PreparedDirective {
+    // What replaces its output, without running it
+    Rendered(String),
+    Pending(PendingDirective),
+}
+
+// A directive ready to be run by the backend
+pub(crate) struct PendingDirective {
+    directive: Directive,
+    shell: bool,
+    // Host directory mounted for it
+    working_dir: PathBuf,
+    container: String,
+    args: Vec<String>,
+    // Where it is in the book, as its streamed lines are prefixed
+    location: String,
+    inline: bool,
+}
+
+pub(crate) enum Executable {
+    Directive(DirectiveRef),
+    Snippet(SnippetRef),
+}
+
+impl Executable {
+    pub(crate) fn range(&self) -> &Range<usize> {
+        match self {
+            Self::Directive(directive) => &directive.range,
+            Self::Snippet(snippet) => &snippet.all_range,
+        }
+    }
+}
+
+/// A directive or snippet that would be run, as listed before building.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExecutableEntry {
+    pub kind: ExecutionKind,
+    /// Chapter source path, relative to the book sources
+    pub chapter: Option<String>,
+    pub line: usize,
+    pub image: String,
+    pub command: String,
+    /// Approves it in untrusted builds once in the allowlist
+    pub digest: String,
+}
+
+impl fmt::Display for ExecutableEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            ExecutionKind::Directive => "directive",
+            ExecutionKind::Snippet => "snippet",
+        };
+        if let Some(chapter) = &self.chapter {
+            write!(f, "{chapter}:")?;
+        }
+        write!(
+            f,
+            "{}: {kind} in image `{}`: {}",
+            self.line,
+            self.image,
+            self.command.trim()
+        )
+    }
+}
+
+// Directives and executable snippets of the content sorted by position,
+// a directive written inside an executable snippet is part of its source
+pub(crate) fn find_executables(content: &str) -> Vec<Executable> {
+    let mut executables: Vec<Executable> = find_directives(content)
+        .into_iter()
+        .map(Executable::Directive)
+        .chain(
+            Snippets::create(content)
+                .executable()
+                .map(Executable::Snippet),
+        )
+        .collect();
+    executables.sort_by_key(|executable| executable.range().start);
+    let mut end: usize = 0;
+    executables.retain(|executable| {
+        let range = executable.range();
+        let keep = range.start >= end;
+        if keep {
+            end = range.end;
+        }
+        keep
+    });
+    executables
 }
 
 const LAUNCH_SHELL_COMMAND: &str = "sh";
 const LAUNCH_SHELL_FLAG: &str = "-c";
 
+// What the engines answer when the image has no shell to run the command, with the
+// exit codes they use for a command which can not be run, never the errors of the command
+fn is_missing_shell(exit_code: Option<i32>, stderr: &str) -> bool {
+    if !matches!(exit_code, Some(126 | 127)) {
+        return false;
+    }
+    stderr.contains(&format!(
+        "exec: \"{LAUNCH_SHELL_COMMAND}\": executable file not found"
+    )) || stderr.contains(&format!(
+        "executable file `{LAUNCH_SHELL_COMMAND}` not found"
+    ))
+}
+
+lazy_static! {
+    // `uid:gid` of the user running the build, `None` where it can not be told
+    static ref HOST_USER_ID: Option<String> = host_user_id();
+}
+
+fn host_user_id() -> Option<String> {
+    let id = |flag: &str| -> Option<String> {
+        let output = Command::new("id")
+            .arg(flag)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        let id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        (output.status.success() && !id.is_empty()).then_some(id)
+    };
+    Some(format!("{}:{}", id("-u")?, id("-g")?))
+}
+
+static CONTAINER_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Containers are named so they can be stopped when their output gets out of hand
+fn container_name() -> String {
+    format!(
+        "ocirun-{}-{}",
+        std::process::id(),
+        CONTAINER_COUNTER.fetch_add(1, Ordering::SeqCst)
+    )
+}
+
+// How an engine invocation is shown in dry-run mode, quoted so it can be pasted in a shell
+pub(crate) fn invocation(engine: &str, args: &[String]) -> String {
+    let words: Vec<&str> = std::iter::once(engine)
+        .chain(args.iter().map(String::as_str))
+        .collect();
+    // Only a nul byte can not be quoted
+    shlex::try_join(words.iter().copied()).unwrap_or_else(|_| words.join(" "))
+}
+
+// Prints the engine command about to be run, at the debug level
+pub(crate) fn echo_command(log_level: LogLevel, command: &Command) {
+    if log_level < LogLevel::Debug {
+        return;
+    }
+    let args: Vec<String> = command
+        .get_args()
+        .map(|arg| arg.to_string_lossy().to_string())
+        .collect();
+    eprintln!(
+        "$ {}",
+        invocation(&command.get_program().to_string_lossy(), &args)
+    );
+}
+
+pub(crate) fn kill_container(engine: &str, container: &str) {
+    let _ = Command::new(engine)
+        .args(["kill", container])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+}
+
+#[cfg(feature = "async")]
+pub(crate) async fn kill_container_async(engine: &str, container: &str) {
+    let _ = tokio::process::Command::new(engine)
+        .args(["kill", container])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await;
+}
+
+#[cfg(feature = "mdbook")]
 impl Preprocessor for OciRun {
     fn name(&self) -> &str {
         "ocirun"
     }
 
     fn supports_renderer(&self, renderer: &str) -> bool {
-        renderer == "html"
+        renderer == "html" || renderer == TEST_RENDERER
     }
 
     fn run(&self, context: &PreprocessorContext, mut book: Book) -> Result<Book> {
-        let key = format!("preprocessor.{}", self.name());
-        let config = context
-            .config
-            .get_deserialized_opt::<OciRunConfig, _>(key)
-            .with_context(|| "Could not deserialize [preprocessor.ocirun]")
-            .unwrap()
-            .unwrap_or(OciRunConfig::default());
-        let preprocessor = config.create_preprocessor(context.root.clone());
-        map_chapter(&mut book, &mut move |chapter| {
-            preprocessor.run_on_chapter(chapter)
-        })?;
+        let config = OciRunConfig::from_book(&context.config, &context.root)?;
+        let mut preprocessor = config.create_preprocessor(context.root.clone())?;
+        // Set by mdbook, with the overrides of the environment
+        preprocessor.src_dir = context.config.book.src.clone();
+        preprocessor.cache_only = config.serve_mode == ServeMode::CacheOnly
+            && context.config.get(LIVE_RELOAD_KEY).is_some();
+        let result = preprocessor.run_on_book(&mut book);
+        preprocessor.finish_build()?;
+        result?;
+        // Under `mdbook test`, failures fail the tests like failed doctests
+        if context.renderer == TEST_RENDERER {
+            preprocessor.log(LogLevel::Normal, preprocessor.report.test_results());
+            preprocessor.check_failures()?;
+        } else if preprocessor.strict {
+            preprocessor.check_failures()?;
+        }
         Ok(book)
     }
 }
 
-lazy_static! {
-    static ref SRC_DIR: String = get_src_dir();
-}
-
 #[derive(Deserialize)]
 struct BookConfig {
     book: BookField,
@@ -126,69 +1003,512 @@ struct BookField {
     src: Option<String>,
 }
 
-fn get_src_dir() -> String {
-    fs::read_to_string(Path::new("book.toml"))
+// The sources of the book at `root`, whatever the current directory
+fn book_src_dir(root: &Path) -> PathBuf {
+    fs::read_to_string(root.join("book.toml"))
         .map_err(|_| None::<String>)
         .and_then(|fc| toml::from_str::<BookConfig>(fc.as_str()).map_err(|_| None))
         .and_then(|bc| bc.book.src.ok_or(None))
-        .unwrap_or_else(|_| String::from("src"))
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("src"))
+}
+
+// The book sources as seen from the root of the repository,
+// which is where the annotations file paths start
+fn github_src_dir(root: &Path, src_dir: &Path) -> PathBuf {
+    let src_dir = root.join(src_dir);
+    std::env::var("GITHUB_WORKSPACE")
+        .ok()
+        .and_then(|workspace| Path::new(&workspace).canonicalize().ok())
+        .zip(src_dir.canonicalize().ok())
+        .and_then(|(workspace, src_dir)| {
+            src_dir.strip_prefix(workspace).map(Path::to_path_buf).ok()
+        })
+        .unwrap_or(src_dir)
 }
 
 impl OciRun {
-    fn run_on_chapter(&self, chapter: &mut Chapter) -> Result<()> {
-        let working_dir = &chapter
-            .path
-            .to_owned()
-            .and_then(|p| {
-                Path::new(SRC_DIR.as_str())
-                    .join(p)
-                    .parent()
-                    .map(PathBuf::from)
+    #[cfg(feature = "mdbook")]
+    pub fn run_on_book(&self, book: &mut Book) -> Result<()> {
+        self.start_progress(book);
+        let result = map_chapter(book, &mut |chapter| {
+            self.run_on_chapter(chapter)?;
+            self.progress.chapter_done();
+            Ok(())
+        });
+        self.progress.finish();
+        result
+    }
+
+    #[cfg(feature = "mdbook")]
+    pub(crate) fn start_progress(&self, book: &Book) {
+        let chapters = book
+            .iter()
+            .filter(|item| matches!(item, BookItem::Chapter(_)))
+            .count();
+        // The streamed lines already show what is going on
+        if self.log_level > LogLevel::Quiet && !self.stream_output {
+            self.progress.start(chapters);
+        }
+    }
+
+    /// Lists every directive and snippet of the book that would be run, without running them.
+    #[cfg(feature = "mdbook")]
+    pub fn list_book(&self, book: &Book) -> Vec<ExecutableEntry> {
+        book.iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(chapter) => Some(chapter),
+                _ => None,
             })
-            .and_then(|p| p.to_str().map(String::from))
-            .unwrap_or_default();
+            .filter(|chapter| !self.is_ignored(chapter))
+            .flat_map(|chapter| {
+                let path = chapter
+                    .source_path
+                    .as_ref()
+                    .map(|path| path.to_string_lossy().to_string());
+                self.list_content(&chapter.content, path)
+            })
+            .collect()
+    }
+
+    pub fn list_content(&self, content: &str, chapter: Option<String>) -> Vec<ExecutableEntry> {
+        find_executables(content)
+            .into_iter()
+            .filter_map(|executable| {
+                let line = content[..executable.range().start].matches('\n').count() + 1;
+                let (kind, image, command, digest) = match executable {
+                    Executable::Directive(directive) => {
+                        let digest = directive_digest(&directive.raw);
+                        let directive = Directive::parse(&directive.raw);
+                        (
+                            ExecutionKind::Directive,
+                            directive.image,
+                            directive.command,
+                            digest,
+                        )
+                    }
+                    // Snippets of languages without config are left untouched
+                    Executable::Snippet(snippet) => {
+                        let lang_config = self.lang_config(&snippet.flags[0])?;
+                        let command = lang_config.command.join(" ");
+                        let digest = snippet_digest(
+                            &lang_config.image,
+                            &command,
+                            &snippet.flags,
+                            snippet.get_source(content),
+                        );
+                        (
+                            ExecutionKind::Snippet,
+                            lang_config.image.clone(),
+                            command,
+                            digest,
+                        )
+                    }
+                };
+                Some(ExecutableEntry {
+                    kind,
+                    chapter: chapter.clone(),
+                    line,
+                    image,
+                    command,
+                    digest,
+                })
+            })
+            .collect()
+    }
 
-        chapter.content = self.run_on_content(&chapter.content, working_dir)?;
+    /// Runs every directive and snippet of the book, going on after errors,
+    /// and lists everything which failed.
+    #[cfg(feature = "mdbook")]
+    pub fn check_book(&self, book: &mut Book) -> Vec<String> {
+        let mut errors = vec![];
+        let _ = map_chapter(book, &mut |chapter| {
+            if let Err(e) = self.run_on_chapter(chapter) {
+                errors.push(format!("{}: {e}", chapter.name));
+            }
+            Ok(())
+        });
+        errors.extend(self.report.failures().iter().map(ToString::to_string));
+        errors
+    }
 
+    // In strict mode the build fails if anything failed
+    pub fn check_failures(&self) -> Result<()> {
+        let failures = self.report.failures();
+        if failures.is_empty() {
+            return Ok(());
+        }
+        let failures: Vec<String> = failures.iter().map(ToString::to_string).collect();
+        bail!(
+            "{} ocirun executions failed:\n{}",
+            failures.len(),
+            failures.join("\n")
+        )
+    }
+
+    /// Prints the summary and writes the configured reports.
+    pub fn finish_build(&self) -> Result<()> {
+        self.log(LogLevel::Normal, self.report.summary());
+        // Colors only for people, not for CI logs or files
+        let colored = io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+        for chapter in self.report.chapter_summaries() {
+            match colored {
+                true => self.log(LogLevel::Normal, chapter.colored()),
+                false => self.log(LogLevel::Normal, chapter),
+            }
+        }
+        if let Some(path) = &self.report_path {
+            self.report.write_json(path)?;
+        }
+        if let Some(path) = &self.junit_path {
+            self.report.write_junit(path)?;
+        }
+        if let Some(path) = &self.log_path {
+            self.report.write_log(path)?;
+        }
+        if std::env::var("GITHUB_ACTIONS").is_ok_and(|value| value == "true") {
+            // stdout belongs to mdbook, the runner reads workflow commands from stderr too
+            let src_dir = github_src_dir(&self.root_path, &self.src_dir);
+            for annotation in self.report.github_annotations(&src_dir) {
+                eprintln!("{annotation}");
+            }
+        }
         Ok(())
     }
 
+    /// Runs every directive and snippet of a markdown file,
+    /// from its directory unless another `working_dir` is given.
+    pub fn run_on_file(&self, file: &Path, working_dir: Option<&Path>) -> Result<String> {
+        let content = fs::read_to_string(file)
+            .with_context(|| format!("Could not read {}", file.display()))?;
+        let working_dir = self.enter_file(file, working_dir);
+        self.run_on_content(&content, &working_dir)
+    }
+
+    // Records the executions from now on in the file, returning the directory they run from
+    pub(crate) fn enter_file(&self, file: &Path, working_dir: Option<&Path>) -> String {
+        self.report
+            .enter_chapter(Some(file.to_string_lossy().to_string()));
+        working_dir
+            .or_else(|| file.parent())
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Directory the commands of a chapter run from, its own under the book sources,
+    /// under the book root whatever the current directory.
+    ///
+    /// With a `source_dir`, it is the one at the same path there, so every translation
+    /// runs against the same scripts and data, unless the chapter only exists in the translation.
+    #[cfg(feature = "mdbook")]
+    pub fn chapter_working_dir(&self, chapter: &Chapter) -> String {
+        let working_dir = |src: &Path| {
+            chapter
+                .path
+                .as_ref()
+                .and_then(|p| self.root_path.join(src).join(p).parent().map(PathBuf::from))
+        };
+        self.source_dir
+            .as_deref()
+            .and_then(working_dir)
+            .filter(|dir| dir.is_dir())
+            .or_else(|| working_dir(&self.src_dir))
+            .unwrap_or(self.root_path.clone())
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Whether the chapter is passed through untouched, without running anything.
+    #[cfg(feature = "mdbook")]
+    pub fn is_ignored(&self, chapter: &Chapter) -> bool {
+        chapter
+            .source_path
+            .as_ref()
+            .is_some_and(|path| self.ignored.matches(path))
+    }
+
+    #[cfg(feature = "mdbook")]
+    fn run_on_chapter(&self, chapter: &mut Chapter) -> Result<()> {
+        if let Some(working_dir) = self.enter_chapter(chapter) {
+            chapter.content = self.run_on_content(&chapter.content, &working_dir)?;
+        }
+        Ok(())
+    }
+
+    // Records the executions from now on in the chapter, returning the directory
+    // its commands run from, unless it is ignored
+    #[cfg(feature = "mdbook")]
+    pub(crate) fn enter_chapter(&self, chapter: &Chapter) -> Option<String> {
+        if self.is_ignored(chapter) {
+            self.log(
+                LogLevel::Verbose,
+                format!("Ignoring chapter `{}`", chapter.name),
+            );
+            return None;
+        }
+        self.report.enter_chapter(
+            chapter
+                .source_path
+                .as_ref()
+                .map(|path| path.to_string_lossy().to_string()),
+        );
+        Some(self.chapter_working_dir(chapter))
+    }
+
     // This method is public for regression tests
     pub fn run_on_content(&self, content: &str, working_dir: &str) -> Result<String> {
-        let mut err = None;
-
-        let mut result = OCIRUN_REG_NEWLINE
-            .replace_all(content, |caps: &Captures| {
-                self.run_ocirun(caps[1].to_string(), working_dir, false)
-                    .unwrap_or_else(|e| {
-                        err = Some(e);
-                        String::new()
-                    })
-            })
-            .to_string();
+        self.run_on_content_at(content, working_dir, 0)
+    }
 
-        if let Some(e) = err {
-            return Err(e);
+    fn run_on_content_at(&self, content: &str, working_dir: &str, depth: usize) -> Result<String> {
+        // Everything is located in the original content first, then each piece
+        // is spliced in place, so outputs are never parsed again
+        let mut result = String::new();
+        let mut begin: usize = 0;
+        for executable in find_executables(content) {
+            self.enter_line(content, &executable, depth);
+            let recorded = self.report.recorded();
+            match executable {
+                Executable::Directive(directive) => {
+                    result.push_str(&content[begin..directive.range.start]);
+                    let output = self.run_directive_at(
+                        &directive.raw,
+                        working_dir,
+                        directive.inline,
+                        depth,
+                    )?;
+                    if !self.hides(recorded) {
+                        result.push_str(&output);
+                    }
+                    begin = directive.range.end;
+                }
+                Executable::Snippet(snippet) => {
+                    result.push_str(&content[begin..snippet.all_range.start]);
+                    let output = self.run_snippet(&snippet, content);
+                    if !self.hides(recorded) {
+                        result.push_str(&content[snippet.all_range.clone()]);
+                        result.push_str(&output.unwrap_or_default());
+                    }
+                    begin = snippet.all_range.end;
+                }
+            }
         }
+        result.push_str(&content[begin..]);
+        Ok(result)
+    }
 
-        result = OCIRUN_REG_INLINE
-            .replace_all(result.as_str(), |caps: &Captures| {
-                self.run_ocirun(caps[1].to_string(), working_dir, true)
-                    .unwrap_or_else(|e| {
-                        err = Some(e);
-                        String::new()
-                    })
-            })
-            .to_string();
+    // Generated content has no line in the chapter, so its executions
+    // are reported at the line of the directive which generated it
+    pub(crate) fn enter_line(&self, content: &str, executable: &Executable, depth: usize) {
+        if depth == 0 {
+            self.report
+                .enter_line(content[..executable.range().start].matches('\n').count() + 1);
+        }
+    }
 
-        result = self.run_snippets_of_content(result.as_str()).unwrap();
+    fn run_directive_at(
+        &self,
+        raw_command: &str,
+        working_dir: &str,
+        inline: bool,
+        depth: usize,
+    ) -> Result<String> {
+        let mut output = self.run_ocirun(raw_command.to_string(), working_dir, inline)?;
+        if !inline {
+            output = self.with_metadata(output);
+        }
+        match self.recurses(raw_command, depth)? {
+            true => self.run_on_content_at(&output, working_dir, depth + 1),
+            false => Ok(output),
+        }
+    }
 
-        match err {
-            None => Ok(result),
-            Some(err) => Err(err),
+    // Whether the output of the directive is run in turn, within the depth limit
+    pub(crate) fn recurses(&self, raw_command: &str, depth: usize) -> Result<bool> {
+        if !Directive::parse(raw_command).flag("recursive")? {
+            return Ok(false);
+        }
+        if depth >= self.max_recursion_depth {
+            bail!(
+                "ocirun recursion depth limit ({}) exceeded by `{}`",
+                self.max_recursion_depth,
+                raw_command.trim()
+            );
+        }
+        Ok(true)
+    }
+
+    // Messages are printed above the progress bar, not through it
+    pub(crate) fn log(&self, level: LogLevel, message: impl fmt::Display) {
+        if level <= self.log_level {
+            self.progress.suspend(|| eprintln!("{message}"));
         }
     }
 
+    // Appends an HTML comment telling how the output was produced, when enabled,
+    // e.g. `<!-- ocirun: rust@sha256:..., 3.2s, cached, 2024-01-01T00:00:00Z -->`
+    pub(crate) fn with_metadata(&self, mut output: String) -> String {
+        if !self.metadata || self.mode == Mode::DryRun {
+            return output;
+        }
+        let Some(execution) = self.report.last() else {
+            return output;
+        };
+        let image = self
+            .digests
+            .lock()
+            .unwrap()
+            .entry(execution.image.clone())
+            .or_insert_with(|| self.resolve_digest(&execution.image))
+            .clone()
+            .unwrap_or(execution.image);
+        let cache = match execution.cache {
+            CacheStatus::Hit => "cached",
+            CacheStatus::Miss | CacheStatus::Disabled => "executed",
+        };
+        let trailing_newline = output.ends_with('\n');
+        if !output.is_empty() && !trailing_newline {
+            output.push('\n');
+        }
+        output.push_str(&format!(
+            "<!-- ocirun: {image}, {:.1}s, {cache}, {} -->",
+            execution.duration.as_secs_f64(),
+            humantime::format_rfc3339_seconds(SystemTime::now())
+        ));
+        if trailing_newline {
+            output.push('\n');
+        }
+        output
+    }
+
+    // In failures-only mode, what succeeded since the `recorded` execution is not rendered
+    pub(crate) fn hides(&self, recorded: usize) -> bool {
+        self.render == Render::FailuresOnly && self.report.succeeded_since(recorded)
+    }
+
+    // Rendered instead of an output which was not produced
+    pub(crate) fn placeholder(&self, message: &str, inline: bool) -> String {
+        match inline {
+            true => format!("*{message}*"),
+            false => format!("```console,placeholder\n{message}\n```\n"),
+        }
+    }
+
+    pub(crate) fn sanitize(&self, output: &str) -> String {
+        sanitize(output, self.strip_bom, self.strip_invisible)
+    }
+
+    // Masks the secrets in what the execution printed
+    fn redact(&self, outcome: ExecutionOutcome) -> ExecutionOutcome {
+        ExecutionOutcome {
+            stdout: self.redactor.redact(&outcome.stdout),
+            stderr: self.redactor.redact(&outcome.stderr),
+            ..outcome
+        }
+    }
+
+    // The host directory mounted for the directive, the `mount` attribute wins over the `mount_scope` option
+    pub(crate) fn mount_dir(&self, directive: &Directive, chapter_dir: &Path) -> Result<PathBuf> {
+        let scope = match directive.attributes.get("mount") {
+            Some(scope) => scope.parse()?,
+            None => self.mount_scope,
+        };
+        let dir = match scope {
+            MountScope::Chapter => return Ok(chapter_dir.to_path_buf()),
+            MountScope::Src => self.root_path.join(&self.src_dir),
+            MountScope::Root => self.root_path.clone(),
+        };
+        dir.canonicalize()
+            .with_context(|| format!("Could not find the directory to mount {}", dir.display()))
+    }
+
+    // The `shell` attribute wins over the image config, images run commands
+    // through the shell unless told otherwise
+    fn uses_shell(&self, directive: &Directive) -> Result<bool> {
+        if directive.attributes.contains_key("shell") {
+            return directive.flag("shell");
+        }
+        Ok(self
+            .images
+            .iter()
+            .find(|config| config.name == directive.image)
+            .map(|config| config.shell)
+            .unwrap_or(true))
+    }
+
+    // Podman maps the user into its user namespace, other engines are given its ids
+    fn user_args(&self, host_user_id: Option<&str>) -> Vec<String> {
+        match self.user.as_str() {
+            IMAGE_USER => vec![],
+            HOST_USER => match host_user_id {
+                Some(_) if self.engine.ends_with("podman") => {
+                    vec!["--userns".into(), "keep-id".into()]
+                }
+                Some(id) => vec!["--user".into(), id.into()],
+                None => vec![],
+            },
+            user => vec!["--user".into(), user.into()],
+        }
+    }
+
+    // Every argument is given to the engine as it is, never through a host shell,
+    // so quotes, carets or `%VARS%` reach the container untouched on every platform
+    fn run_args(
+        &self,
+        directive: &Directive,
+        working_dir: &str,
+        name: &str,
+        shell: bool,
+    ) -> Result<Vec<String>> {
+        let network = directive
+            .attributes
+            .get("network")
+            .unwrap_or(&self.default_network);
+        let mut args: Vec<String> = vec![
+            "run".into(),
+            "--rm".into(),
+            "--name".into(),
+            name.into(),
+            "--network".into(),
+            network.into(),
+        ];
+        if let Some(runtime) = &self.runtime {
+            args.extend(["--runtime".into(), runtime.clone()]);
+        }
+        args.extend(self.user_args(HOST_USER_ID.as_deref()));
+        let limits = ResourceLimits::from_attributes(|name| {
+            directive.attributes.get(name).map(String::as_str)
+        })?;
+        args.extend(self.resource_limits.overridden_by(&limits).engine_args());
+        args.extend(self.security.engine_args());
+        args.extend(self.secrets.engine_args());
+        args.extend([
+            "-w".into(),
+            working_dir.into(),
+            "-v".into(),
+            match directive.flag("writable")? {
+                true => format!("{0:}:{0:}", working_dir),
+                false => format!("{0:}:{0:}:ro", working_dir),
+            },
+            directive.image.clone(),
+        ]);
+        let cmd = directive.command.as_str();
+        match shell {
+            true => args.extend([
+                LAUNCH_SHELL_COMMAND.into(),
+                LAUNCH_SHELL_FLAG.into(),
+                cmd.into(),
+            ]),
+            false => args.extend(
+                shlex::split(cmd)
+                    .with_context(|| format!("Could not split `{cmd}` into arguments"))?,
+            ),
+        };
+        Ok(args)
+    }
+
     // This method is public for unit tests
     pub fn run_ocirun(
         &self,
@@ -196,43 +1516,206 @@ impl OciRun {
         working_dir: &str,
         inline: bool,
     ) -> Result<String> {
-        let absolute_working_dir = Path::new(working_dir).canonicalize().unwrap();
-        //let output = Command::new(LAUNCH_SHELL_COMMAND)
-        //    .args([LAUNCH_SHELL_FLAG, &command])
-        //    .current_dir(working_dir)
-        //    .output()
-        //    .with_context(|| "Fail to run shell")?;
-        let (image, cmd) = raw_command
-            .split_once(' ')
-            .unwrap_or(("alpine", raw_command.as_str()));
-        let mut command = Command::new(self.engine.as_str());
-        command.stdin(Stdio::null()).args([
-            "run",
-            "--rm",
-            "-w",
-            absolute_working_dir.to_str().unwrap(),
-            "-v",
-            format!("{0:}:{0:}", absolute_working_dir.to_str().unwrap()).as_str(),
-            "-t",
-            image,
-            LAUNCH_SHELL_COMMAND,
-            LAUNCH_SHELL_FLAG,
-            cmd,
-        ]);
-        eprintln!(">>>>>>>>> {:?}", &command);
+        let pending = match self.prepare_directive(&raw_command, working_dir, inline)? {
+            PreparedDirective::Rendered(output) => return Ok(output),
+            PreparedDirective::Pending(pending) => pending,
+        };
+        let started = Instant::now();
+        let output = self.backend.run_directive(&self.directive_run(&pending));
+        self.finish_directive(pending, output, started.elapsed())
+    }
 
-        let output = command.output().with_context(|| "Fail to run shell")?;
+    // Checks everything which could keep the directive from running, rendering
+    // what replaces its output when something does, like a placeholder or a refusal
+    pub(crate) fn prepare_directive(
+        &self,
+        raw_command: &str,
+        working_dir: &str,
+        inline: bool,
+    ) -> Result<PreparedDirective> {
+        if self.skip {
+            return Ok(PreparedDirective::Rendered(
+                self.placeholder(SKIPPED, inline),
+            ));
+        }
+        let chapter_dir = Path::new(working_dir)
+            .canonicalize()
+            .with_context(|| format!("Working directory {working_dir} not found"))?;
+        let directive = Directive::parse(raw_command);
+        let (image, cmd) = (directive.image.as_str(), directive.command.as_str());
+        let shell = self.uses_shell(&directive)?;
+        let absolute_working_dir = self.mount_dir(&directive, &chapter_dir)?;
+        let name = container_name();
+        let args = self.run_args(
+            &directive,
+            absolute_working_dir.to_str().unwrap(),
+            &name,
+            shell,
+        )?;
+        // Directive outputs are never cached
+        if self.cache_only {
+            return Ok(PreparedDirective::Rendered(
+                self.placeholder(NOT_EXECUTED, inline),
+            ));
+        }
+        if self.mode == Mode::DryRun {
+            let invocation = invocation(&self.engine, &args);
+            self.log(LogLevel::Normal, format!("Dry run: {invocation}"));
+            return Ok(PreparedDirective::Rendered(match inline {
+                true => format!("`{invocation}`"),
+                false => format!("```console,dry-run\n$ {invocation}\n```\n"),
+            }));
+        }
+        self.log(
+            LogLevel::Verbose,
+            format!("Running `{}` in image `{image}`", cmd.trim()),
+        );
+        let what = format!("ocirun command `{cmd}` in image `{image}`");
+        if let Some(message) = self.unapproved(&directive_digest(raw_command), &what) {
+            return Ok(PreparedDirective::Rendered(
+                self.placeholder(&message, inline),
+            ));
+        }
+        if let Some(error) = self.refusal(ExecutionKind::Directive, image, cmd) {
+            self.log(
+                LogLevel::Normal,
+                format!("Warning: ocirun command `{cmd}` in image `{image}` refused: {error}"),
+            );
+            return Ok(PreparedDirective::Rendered(match inline {
+                true => error,
+                false => format!("```console,error\n{error}\n```\n"),
+            }));
+        }
+        self.progress.running(image, cmd);
+        self.progress.suspend(|| {
+            echo_command(
+                self.log_level,
+                Command::new(self.engine.as_str()).args(&args),
+            )
+        });
+        Ok(PreparedDirective::Pending(PendingDirective {
+            shell,
+            working_dir: absolute_working_dir,
+            container: name,
+            args,
+            location: self.report.location(),
+            inline,
+            directive,
+        }))
+    }
 
-        eprintln!(">>>>>>>>> {:?}", &output);
+    // What the backend is given to run the directive
+    pub(crate) fn directive_run<'a>(&'a self, pending: &'a PendingDirective) -> DirectiveRun<'a> {
+        DirectiveRun {
+            image: &pending.directive.image,
+            command: &pending.directive.command,
+            working_dir: &pending.working_dir,
+            shell: pending.shell,
+            container: &pending.container,
+            engine_args: &pending.args,
+            limits: &self.capture_limits,
+            echo: Some(pending.location.as_str()).filter(|_| self.stream_output),
+            redactor: &self.redactor,
+        }
+    }
 
-        let stdout = format_whitespace(String::from_utf8_lossy(&output.stdout), inline)
-            .replace("\r\n", "\n");
+    // Records how the directive ran and renders its output
+    pub(crate) fn finish_directive(
+        &self,
+        pending: PendingDirective,
+        output: ExecutionResult,
+        duration: Duration,
+    ) -> Result<String> {
+        let PendingDirective {
+            directive,
+            shell,
+            inline,
+            ..
+        } = pending;
+        let (image, cmd) = (directive.image.as_str(), directive.command.as_str());
+        self.progress.done(false);
+        let output = output
+            .map(|outcome| self.redact(outcome))
+            .map_err(|error| match error {
+                ExecutionError::Failed(outcome) => ExecutionError::Failed(self.redact(outcome)),
+                error => error,
+            });
+        let outcome = match &output {
+            Ok(outcome) => Some(outcome.clone()),
+            Err(error) => error.outcome().cloned(),
+        };
+        self.record(Execution {
+            duration,
+            exit_code: outcome.as_ref().and_then(|outcome| outcome.exit_code),
+            success: output.is_ok(),
+            error: match &output {
+                Ok(_) => None,
+                Err(ExecutionError::Failed(outcome)) => Some(outcome.stderr.clone()),
+                Err(error) => Some(error.to_string()),
+            },
+            failure: output.as_ref().err().map(ExecutionError::failure_kind),
+            outcome,
+            ..Execution::new(ExecutionKind::Directive, image, cmd)
+        });
+        let outcome = match output {
+            Ok(outcome) => outcome,
+            Err(ExecutionError::Failed(outcome)) => outcome,
+            Err(ExecutionError::LimitExceeded(exceeded)) => {
+                self.log(
+                    LogLevel::Normal,
+                    format!(
+                        "Warning: ocirun command `{}` in image `{}` stopped: {}",
+                        cmd,
+                        image,
+                        exceeded.message()
+                    ),
+                );
+                return Ok(match inline {
+                    true => exceeded.message(),
+                    false => format!("```console,error\n{}\n```\n", exceeded.message()),
+                });
+            }
+            Err(error) => return Err(error.into()),
+        };
 
-        // let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        let mut stdout = format_whitespace(
+            self.sanitize(&outcome.stdout).into(),
+            inline,
+            self.trailing_newlines,
+        );
 
-        // eprintln!("command: {}", command);
-        // eprintln!("stdout: {:?}", stdout);
-        // eprintln!("stderr: {:?}", stderr);
+        if !outcome.is_success() {
+            let stderr = outcome.stderr;
+            if shell && is_missing_shell(outcome.exit_code, &stderr) {
+                bail!(
+                    "Image `{image}` has no `{LAUNCH_SHELL_COMMAND}` to run `{cmd}`, \
+                     add `shell=false` to the directive or set `shell = false` \
+                     for it under [[preprocessor.ocirun.images]]"
+                );
+            }
+            self.log(
+                LogLevel::Normal,
+                format!(
+                    "Warning: ocirun command `{}` in image `{}` failed ({}):\n{}",
+                    cmd,
+                    image,
+                    outcome
+                        .exit_code
+                        .map_or("killed by a signal".into(), |code| format!(
+                            "exit status: {code}"
+                        )),
+                    stderr
+                ),
+            );
+            // Reviewers of the failures need to see why
+            if self.render == Render::FailuresOnly && !inline {
+                if !stdout.is_empty() && !stdout.ends_with('\n') {
+                    stdout.push('\n');
+                }
+                stdout.push_str(&format!("```console,error\n{}\n```\n", stderr.trim_end()));
+                return Ok(stdout);
+            }
+        }
 
         Ok(stdout)
     }
@@ -240,16 +1723,145 @@ impl OciRun {
 
 #[cfg(test)]
 mod tests {
-    use crate::{ocirun::LangConfig, OciRunConfig};
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use super::{
+        find_executables, is_missing_shell, Executable, ExecutableEntry, ImageConfig, LogLevel,
+        Mode, MountScope, RedactRule, Render, ResourceLimits, SecretSource, SecurityOptions,
+        ServeMode, Severity, TrailingNewlines,
+    };
+
+    use crate::{
+        allowlist::{directive_digest, snippet_digest},
+        directive::Directive,
+        mock::MockEngine,
+        ocirun::LangConfig,
+        report::{CacheStatus, Execution, ExecutionKind},
+        OciRun, OciRunConfig,
+    };
+
+    #[test]
+    pub fn test_find_executables() {
+        let content = r#"
+```python,ocirun
+print("<!-- ocirun alpine ls -->")
+```
+<!-- ocirun alpine echo '```python,ocirun' -->
+```rust
+fn main() {}
+```
+"#;
+        let executables = find_executables(content);
+        assert_eq!(executables.len(), 2);
+        assert!(matches!(executables[0], Executable::Snippet(_)));
+        match &executables[1] {
+            Executable::Directive(directive) => {
+                assert_eq!(directive.raw, "alpine echo '```python,ocirun' ")
+            }
+            Executable::Snippet(_) => panic!("expected a directive"),
+        }
+    }
 
     #[test]
     pub fn test_deserialize_config() {
         let expected = OciRunConfig {
             engine: Some("podman".into()),
-            langs: vec![LangConfig::rust(), LangConfig::rust()],
+            langs: vec![
+                LangConfig::rust(),
+                LangConfig {
+                    severity: Severity::Warn,
+                    network: Some("bridge".into()),
+                    limits: ResourceLimits {
+                        memory: Some("2g".into()),
+                        ..Default::default()
+                    },
+                    ..LangConfig::rust()
+                },
+            ],
+            images: vec![ImageConfig {
+                name: "gcr.io/distroless/static".into(),
+                shell: false,
+            }],
+            max_output_bytes: Some(1024),
+            max_output_lines: Some(100),
+            timeout: Some(60),
+            max_recursion_depth: Some(2),
+            trailing_newlines: TrailingNewlines::Single,
+            strip_bom: Some(false),
+            strip_invisible: Some(true),
+            redact: vec![
+                RedactRule::Env("GITHUB_TOKEN".into()),
+                "re:sk-[A-Za-z0-9]+".parse().unwrap(),
+            ],
+            report: Some("target/ocirun.json".into()),
+            junit: Some("target/ocirun.xml".into()),
+            strict: true,
+            mode: Mode::DryRun,
+            log_level: LogLevel::Debug,
+            stream_output: true,
+            metadata: true,
+            serve_mode: ServeMode::CacheOnly,
+            render: Render::FailuresOnly,
+            log_file: Some("target/ocirun.log".into()),
+            default_network: Some("host".into()),
+            limits: ResourceLimits {
+                memory: Some("512m".into()),
+                cpus: Some(1.0),
+                pids: Some(128),
+            },
+            security: SecurityOptions {
+                cap_drop: vec!["ALL".into()],
+                seccomp: Some("profile.json".into()),
+                no_new_privileges: true,
+            },
+            user: Some("1000:1000".into()),
+            runtime: Some("runsc".into()),
+            secrets: BTreeMap::from([
+                ("API_KEY".into(), SecretSource::Env("OPENAI_API_KEY".into())),
+                ("CERT".into(), SecretSource::File("certs/cert.pem".into())),
+            ]),
+            untrusted: true,
+            allowlist: Some("ocirun.allow".into()),
+            source_dir: Some("src".into()),
+            cache_dir: Some("../.ocirun-cache".into()),
+            workspace: Some("../ocirun.toml".into()),
+            mount_scope: MountScope::Root,
+            ignore: vec!["drafts/**".into()],
         };
         let toml_config = r#"
         engine = "podman"
+        max_output_bytes = 1024
+        max_output_lines = 100
+        timeout = 60
+        max_recursion_depth = 2
+        trailing_newlines = "single"
+        strip_bom = false
+        strip_invisible = true
+        redact = ["GITHUB_TOKEN", "re:sk-[A-Za-z0-9]+"]
+        report = "target/ocirun.json"
+        junit = "target/ocirun.xml"
+        strict = true
+        mode = "dry-run"
+        log_level = "debug"
+        stream_output = true
+        metadata = true
+        serve_mode = "cache-only"
+        render = "failures-only"
+        log_file = "target/ocirun.log"
+        default_network = "host"
+        limits = { memory = "512m", cpus = 1.0, pids = 128 }
+        security = { cap_drop = ["ALL"], seccomp = "profile.json", no_new_privileges = true }
+        user = "1000:1000"
+        runtime = "runsc"
+        secrets = { API_KEY = { env = "OPENAI_API_KEY" }, CERT = { file = "certs/cert.pem" } }
+        untrusted = true
+        allowlist = "ocirun.allow"
+        source_dir = "src"
+        cache_dir = "../.ocirun-cache"
+        workspace = "../ocirun.toml"
+        mount_scope = "root"
+        ignore = ["drafts/**"]
         [[langs]]
         name = "rust"
         image = "rust"
@@ -258,8 +1870,618 @@ mod tests {
         name = "rust"
         image = "rust"
         command = ["/bin/bash", "-ec", "rustc source -o binary && ./binary < input"]
+        severity = "warn"
+        network = "bridge"
+        limits = { memory = "2g" }
+        [[images]]
+        name = "gcr.io/distroless/static"
+        shell = false
         "#;
         let config: OciRunConfig = toml::from_str(toml_config).unwrap();
         assert_eq!(config, expected);
     }
+
+    #[test]
+    pub fn test_list_content() {
+        let content = r#"# Title
+<!-- ocirun python python script.py -->
+```rust,ocirun
+fn main() {}
+```
+```python,ocirun
+print("no config")
+```
+"#;
+        let config = OciRunConfig {
+            langs: vec![LangConfig::rust()],
+            ..Default::default()
+        };
+        let entries = config
+            .create_preprocessor(".".into())
+            .unwrap()
+            .list_content(content, Some("intro.md".into()));
+        assert_eq!(
+            entries,
+            vec![
+                ExecutableEntry {
+                    kind: ExecutionKind::Directive,
+                    chapter: Some("intro.md".into()),
+                    line: 2,
+                    image: "python".into(),
+                    command: "python script.py ".into(),
+                    digest: directive_digest("python python script.py"),
+                },
+                ExecutableEntry {
+                    kind: ExecutionKind::Snippet,
+                    chapter: Some("intro.md".into()),
+                    line: 3,
+                    image: "rust".into(),
+                    command: "/bin/bash -ec rustc source -o binary && ./binary < input".into(),
+                    digest: snippet_digest(
+                        "rust",
+                        "/bin/bash -ec rustc source -o binary && ./binary < input",
+                        &["rust".into(), "ocirun".into()],
+                        "\nfn main() {}\n",
+                    ),
+                },
+            ]
+        );
+        assert_eq!(
+            entries[0].to_string(),
+            "intro.md:2: directive in image `python`: python script.py"
+        );
+    }
+
+    #[test]
+    pub fn test_load() {
+        let root = std::env::temp_dir().join(format!("ocirun-load-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        assert_eq!(OciRunConfig::load(&root).unwrap(), OciRunConfig::default());
+        std::fs::write(
+            root.join("book.toml"),
+            "[book]\ntitle = \"Book\"\n\n[preprocessor.ocirun]\nengine = \"podman\"\nstrict = true\n",
+        )
+        .unwrap();
+        let config = OciRunConfig::load(&root).unwrap();
+        std::fs::write(
+            root.join("book.toml"),
+            "[preprocessor.ocirun]\nignore = [\"drafts/[\"]\n",
+        )
+        .unwrap();
+        let invalid = OciRunConfig::load(&root);
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            config,
+            OciRunConfig {
+                engine: Some("podman".into()),
+                strict: true,
+                ..Default::default()
+            }
+        );
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    pub fn test_load_workspace() {
+        let workspace =
+            std::env::temp_dir().join(format!("ocirun-workspace-{}", std::process::id()));
+        let root = workspace.join("guide");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            workspace.join("ocirun.toml"),
+            "engine = \"docker\"\nstrict = true\ncache_dir = \".ocirun-cache\"\n\
+             [[langs]]\nname = \"rust\"\nimage = \"rust\"\ncommand = [\"cargo\", \"run\"]\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("book.toml"),
+            "[preprocessor.ocirun]\nworkspace = \"../ocirun.toml\"\nengine = \"podman\"\nstrict = false\n",
+        )
+        .unwrap();
+        let config = OciRunConfig::load(&root).unwrap();
+        std::fs::remove_dir_all(&workspace).unwrap();
+        // The book wins, the paths of the workspace are made relative to the book
+        assert_eq!(config.engine.as_deref(), Some("podman"));
+        assert!(!config.strict);
+        assert_eq!(config.cache_dir, Some("../.ocirun-cache".into()));
+        assert_eq!(config.langs[0].name, "rust");
+        assert_eq!(config.workspace, Some("../ocirun.toml".into()));
+    }
+
+    #[test]
+    pub fn test_resolved_config() {
+        let config: OciRunConfig = toml::from_str(
+            r#"
+            engine = "podman"
+            strip_bom = false
+            "#,
+        )
+        .unwrap();
+        let resolved = config.resolved();
+        assert_eq!(resolved.engine.as_deref(), Some("podman"));
+        assert_eq!(resolved.strip_bom, Some(false));
+        assert_eq!(resolved.max_recursion_depth, Some(3));
+        assert_eq!(
+            config.defaulted(),
+            vec![
+                "max_output_bytes",
+                "max_recursion_depth",
+                "strip_invisible",
+                "default_network",
+                "user"
+            ]
+        );
+        assert_eq!(resolved.default_network.as_deref(), Some("none"));
+        // the resolved config can be written back as a book.toml table
+        let written: OciRunConfig = toml::from_str(&toml::to_string(&resolved).unwrap()).unwrap();
+        assert_eq!(written, resolved);
+    }
+
+    #[test]
+    pub fn test_dry_run() {
+        let preprocessor = OciRunConfig {
+            langs: vec![LangConfig::rust()],
+            mode: Mode::DryRun,
+            ..Default::default()
+        }
+        .create_preprocessor(".".into())
+        .unwrap();
+        let content =
+            "<!-- ocirun ubuntu echo 'hi there' -->\n\n```rust,ocirun\nfn main() {}\n```\n";
+        let result = preprocessor.run_on_content(content, ".").unwrap();
+        let dir = std::env::current_dir().unwrap();
+        let dir = dir.to_str().unwrap();
+        assert!(result.starts_with("```console,dry-run\n$ docker run --rm --name ocirun-"));
+        assert!(result.contains(&format!(
+            " -w {dir} -v {dir}:{dir}:ro ubuntu sh -c \"echo 'hi there' \"\n```\n"
+        )));
+        assert!(result.contains(
+            "```console,dry-run\n\
+             $ docker create --rm --network none -w /root rust /bin/bash -ec 'rustc source -o binary && ./binary < input'\n"
+        ));
+        assert!(result.ends_with("$ docker start -a '<container>'\n```\n"));
+        assert!(preprocessor.report.executions().is_empty());
+    }
+
+    #[test]
+    pub fn test_secrets() {
+        std::env::set_var("OCIRUN_TEST_OCIRUN_SECRET", "t0k3n");
+        let secrets = BTreeMap::from([(
+            "TOKEN".into(),
+            SecretSource::Env("OCIRUN_TEST_OCIRUN_SECRET".into()),
+        )]);
+        let preprocessor = OciRunConfig {
+            mode: Mode::DryRun,
+            secrets: secrets.clone(),
+            ..Default::default()
+        }
+        .create_preprocessor(".".into())
+        .unwrap();
+        let result = preprocessor
+            .run_on_content("<!-- ocirun alpine env -->", ".")
+            .unwrap();
+        assert!(result.contains(" --env TOKEN "));
+        assert!(!result.contains("t0k3n"));
+
+        let engine = MockEngine::new().directive("alpine", "env", Ok("TOKEN=t0k3n\n"));
+        let preprocessor = OciRunConfig {
+            secrets,
+            ..Default::default()
+        }
+        .create_preprocessor(".".into())
+        .unwrap()
+        .with_backend(engine);
+        assert_eq!(
+            preprocessor
+                .run_on_content("<!-- ocirun alpine env -->", ".")
+                .unwrap(),
+            "TOKEN=[REDACTED]"
+        );
+    }
+
+    #[test]
+    pub fn test_redact_directive_stdout() {
+        let engine = MockEngine::new().directive(
+            "alpine",
+            "cat config",
+            Ok("key = \"sk-abc123\"\nhost = example.com\n"),
+        );
+        let preprocessor = OciRun::builder()
+            .redact("re:sk-[a-z0-9]+".parse().unwrap())
+            .backend(engine)
+            .build()
+            .unwrap();
+        assert_eq!(
+            preprocessor
+                .run_on_content("<!-- ocirun alpine cat config -->\n", ".")
+                .unwrap(),
+            "key = \"[REDACTED]\"\nhost = example.com\n"
+        );
+        let recorded = preprocessor.report.last().unwrap();
+        assert_eq!(
+            recorded.outcome.unwrap().stdout,
+            "key = \"[REDACTED]\"\nhost = example.com\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "mdbook")]
+    pub fn test_chapter_working_dir() {
+        use mdbook::book::Chapter;
+
+        let chapter = |path: &str| Chapter::new("Chapter", String::new(), path, vec![]);
+        let preprocessor = OciRunConfig::default()
+            .create_preprocessor(".".into())
+            .unwrap();
+        assert_eq!(
+            preprocessor.chapter_working_dir(&chapter("shell/input.md")),
+            "./src/shell"
+        );
+        // Like a translation running against the sources of the original language
+        let preprocessor = OciRunConfig {
+            source_dir: Some("tests/regression".into()),
+            ..Default::default()
+        }
+        .create_preprocessor(".".into())
+        .unwrap();
+        assert_eq!(
+            preprocessor.chapter_working_dir(&chapter("shell/input.md")),
+            "./tests/regression/shell"
+        );
+        assert_eq!(
+            preprocessor.chapter_working_dir(&chapter("translated/only.md")),
+            "./src/translated"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "mdbook")]
+    pub fn test_ignored_chapters() {
+        use mdbook::book::{Book, BookItem, Chapter};
+
+        let engine = MockEngine::new().directive("alpine", "echo hi", Ok("hi\n"));
+        let preprocessor = OciRunConfig {
+            ignore: vec!["drafts/**".into()],
+            ..Default::default()
+        }
+        .create_preprocessor(".".into())
+        .unwrap()
+        .with_backend(engine);
+        let directive = "<!-- ocirun alpine echo hi -->\n";
+        let mut book = Book::new();
+        for path in ["intro.md", "drafts/broken.md"] {
+            book.push_item(Chapter::new(path, directive.into(), path, vec![]));
+        }
+        assert_eq!(preprocessor.list_book(&book).len(), 1);
+        assert_eq!(preprocessor.stats_book(&book).chapters.len(), 1);
+        preprocessor.run_on_book(&mut book).unwrap();
+        let contents: Vec<&str> = book
+            .iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(chapter) => Some(chapter.content.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(contents, ["hi\n", directive]);
+    }
+
+    #[test]
+    pub fn test_cache_only() {
+        let mut preprocessor = OciRunConfig {
+            langs: vec![LangConfig::rust()],
+            ..Default::default()
+        }
+        .create_preprocessor(".".into())
+        .unwrap();
+        preprocessor.cache_only = true;
+        let content =
+            "<!-- ocirun echo hi -->\n\n```rust,ocirun\nfn main() { /* test_cache_only */ }\n```\n";
+        assert_eq!(
+            preprocessor.run_on_content(content, ".").unwrap(),
+            "```console,placeholder\nnot yet executed, run `mdbook-ocirun warm`\n```\n\n\
+             ```rust,ocirun\nfn main() { /* test_cache_only */ }\n```\n\
+             ```console,placeholder\nnot yet executed, run `mdbook-ocirun warm`\n```\n"
+        );
+        assert!(preprocessor.report.executions().is_empty());
+    }
+
+    #[test]
+    pub fn test_skip() {
+        let mut preprocessor = OciRunConfig {
+            langs: vec![LangConfig::rust()],
+            ..Default::default()
+        }
+        .create_preprocessor(".".into())
+        .unwrap();
+        preprocessor.skip = true;
+        let content = "Today is <!-- ocirun date -->.\n\n```rust,ocirun\nfn main() {}\n```\n";
+        assert_eq!(
+            preprocessor.run_on_content(content, ".").unwrap(),
+            "Today is *not executed, OCIRUN_SKIP is set*.\n\n\
+             ```rust,ocirun\nfn main() {}\n```\n\
+             ```console,placeholder\nnot executed, OCIRUN_SKIP is set\n```\n"
+        );
+        assert!(preprocessor.report.executions().is_empty());
+    }
+
+    #[test]
+    pub fn test_failures_only() {
+        let content = "Before\n<!-- ocirun echo hi -->\nAfter\n";
+        let render = |engine: &str| {
+            OciRunConfig {
+                engine: Some(engine.into()),
+                render: Render::FailuresOnly,
+                ..Default::default()
+            }
+            .create_preprocessor(".".into())
+            .unwrap()
+            .run_on_content(content, ".")
+            .unwrap()
+        };
+        assert_eq!(render("true"), "Before\nAfter\n");
+        assert_eq!(render("false"), "Before\n```console,error\n\n```\nAfter\n");
+    }
+
+    #[test]
+    pub fn test_with_metadata() {
+        let preprocessor = OciRunConfig {
+            engine: Some("false".into()),
+            metadata: true,
+            ..Default::default()
+        }
+        .create_preprocessor(".".into())
+        .unwrap();
+        assert_eq!(preprocessor.with_metadata("hi\n".into()), "hi\n");
+        preprocessor.report.record(Execution {
+            duration: Duration::from_millis(3240),
+            cache: CacheStatus::Hit,
+            ..Execution::new(ExecutionKind::Snippet, "rust", "cargo run")
+        });
+        // Without a digest the image is given as it is
+        let output = preprocessor.with_metadata("hi\n".into());
+        assert!(output.starts_with("hi\n<!-- ocirun: rust, 3.2s, cached, "));
+        assert!(output.ends_with("Z -->\n"));
+        let output = preprocessor.with_metadata("\n```console,success\nhi\n```".into());
+        assert!(output.starts_with("\n```console,success\nhi\n```\n<!-- ocirun: rust, "));
+        assert!(output.ends_with("Z -->"));
+    }
+
+    #[test]
+    pub fn test_uses_shell() {
+        let config: OciRunConfig = toml::from_str(
+            r#"
+            [[images]]
+            name = "distroless"
+            shell = false
+            [[images]]
+            name = "alpine"
+            "#,
+        )
+        .unwrap();
+        let preprocessor = config.create_preprocessor(".".into()).unwrap();
+        let uses_shell = |raw| preprocessor.uses_shell(&Directive::parse(raw)).unwrap();
+        assert!(uses_shell("alpine ls"));
+        assert!(uses_shell("fedora ls"));
+        assert!(!uses_shell("distroless /app"));
+        assert!(!uses_shell("shell=false alpine ls"));
+        assert!(uses_shell("shell=true distroless /app"));
+    }
+
+    #[test]
+    pub fn test_missing_working_dir() {
+        let engine = MockEngine::new().directive("alpine", "ls", Ok(""));
+        let preprocessor = OciRun::builder().backend(engine).build().unwrap();
+        let error = preprocessor
+            .run_on_content("<!-- ocirun alpine ls -->\n", "./no-such-dir")
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Working directory ./no-such-dir not found"
+        );
+    }
+
+    #[test]
+    pub fn test_is_missing_shell() {
+        assert!(is_missing_shell(
+            Some(127),
+            "docker: Error response from daemon: failed to create task for container: \
+             exec: \"sh\": executable file not found in $PATH: unknown."
+        ));
+        assert!(is_missing_shell(
+            Some(127),
+            "Error: crun: executable file `sh` not found in $PATH: No such file or directory: \
+             OCI runtime attempted to invoke a command that was not found\nsh: no such file or directory"
+        ));
+        assert!(!is_missing_shell(
+            Some(2),
+            "ls: cannot access 'x': No such file or directory"
+        ));
+        assert!(!is_missing_shell(
+            Some(127),
+            "bash: run.sh: No such file or directory"
+        ));
+        assert!(!is_missing_shell(
+            Some(1),
+            "exec: \"sh\": executable file not found in $PATH"
+        ));
+    }
+
+    #[test]
+    pub fn test_resource_limits() {
+        let preprocessor = OciRunConfig {
+            langs: vec![LangConfig {
+                limits: ResourceLimits {
+                    memory: Some("2g".into()),
+                    ..Default::default()
+                },
+                ..LangConfig::rust()
+            }],
+            limits: ResourceLimits {
+                memory: Some("512m".into()),
+                cpus: Some(1.5),
+                pids: Some(128),
+            },
+            mode: Mode::DryRun,
+            ..Default::default()
+        }
+        .create_preprocessor(".".into())
+        .unwrap();
+        let content = "<!-- ocirun pids=256 alpine ls -->\n\
+                       ```rust,ocirun,cpus=2\nfn main() {}\n```\n";
+        let result = preprocessor.run_on_content(content, ".").unwrap();
+        assert!(result.contains(" --memory 512m --cpus 1.5 --pids-limit 256 -w "));
+        assert!(result.contains(" --memory 2g --cpus 2 --pids-limit 128 -w /root rust "));
+
+        let error = preprocessor
+            .run_on_content("<!-- ocirun cpus=many alpine ls -->", ".")
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid value `many` for ocirun attribute `cpus`"
+        );
+    }
+
+    #[test]
+    pub fn test_security_options() {
+        let preprocessor = OciRunConfig {
+            langs: vec![LangConfig::rust()],
+            security: SecurityOptions {
+                cap_drop: vec!["ALL".into()],
+                seccomp: Some("profile.json".into()),
+                no_new_privileges: true,
+            },
+            mode: Mode::DryRun,
+            ..Default::default()
+        }
+        .create_preprocessor("/book".into())
+        .unwrap();
+        let content = "<!-- ocirun alpine ls -->\n```rust,ocirun\nfn main() {}\n```\n";
+        let result = preprocessor.run_on_content(content, ".").unwrap();
+        let args = " --cap-drop ALL --security-opt 'seccomp=/book/profile.json' \
+                    --security-opt no-new-privileges -w ";
+        assert_eq!(result.matches(args).count(), 2);
+    }
+
+    #[test]
+    pub fn test_mount_dir() {
+        let root = std::env::current_dir().unwrap();
+        let chapter_dir = root.join("src/utils");
+        let preprocessor = OciRunConfig::default()
+            .create_preprocessor(".".into())
+            .unwrap();
+        let mount_dir =
+            |directive: &str| preprocessor.mount_dir(&Directive::parse(directive), &chapter_dir);
+        assert_eq!(mount_dir("alpine ls").unwrap(), chapter_dir);
+        assert_eq!(mount_dir("mount=src alpine ls").unwrap(), root.join("src"));
+        assert_eq!(mount_dir("mount=root alpine tools/gen.sh").unwrap(), root);
+        assert_eq!(
+            mount_dir("mount=home alpine ls").unwrap_err().to_string(),
+            "Invalid value `home` for ocirun attribute `mount`, expected chapter, src or root"
+        );
+        let preprocessor = OciRunConfig {
+            mount_scope: MountScope::Root,
+            ..Default::default()
+        }
+        .create_preprocessor(".".into())
+        .unwrap();
+        let directive = Directive::parse("mount=chapter alpine ls");
+        assert_eq!(
+            preprocessor.mount_dir(&directive, &chapter_dir).unwrap(),
+            chapter_dir
+        );
+        let directive = Directive::parse("alpine ls");
+        assert_eq!(
+            preprocessor.mount_dir(&directive, &chapter_dir).unwrap(),
+            root
+        );
+    }
+
+    #[test]
+    pub fn test_run_args() {
+        let preprocessor = OciRunConfig {
+            user: Some("image".into()),
+            ..Default::default()
+        }
+        .create_preprocessor(".".into())
+        .unwrap();
+        let directive = Directive::parse(r#"alpine echo "a \"b\"" ^c %PATH% & echo 'd'"#);
+        let args = preprocessor
+            .run_args(&directive, "/book/src", "ocirun-0", true)
+            .unwrap();
+        assert_eq!(
+            args,
+            vec![
+                "run",
+                "--rm",
+                "--name",
+                "ocirun-0",
+                "--network",
+                "none",
+                "-w",
+                "/book/src",
+                "-v",
+                "/book/src:/book/src:ro",
+                "alpine",
+                "sh",
+                "-c",
+                r#"echo "a \"b\"" ^c %PATH% & echo 'd'"#,
+            ]
+        );
+
+        let directive = Directive::parse(r#"shell=false distroless /app "a \"b\"" ^c %PATH%"#);
+        let args = preprocessor
+            .run_args(&directive, "/book/src", "ocirun-0", false)
+            .unwrap();
+        assert_eq!(
+            args[10..],
+            ["distroless", "/app", "a \"b\"", "^c", "%PATH%"]
+        );
+
+        let directive = Directive::parse("network=bridge alpine wget -qO- example.com");
+        let args = preprocessor
+            .run_args(&directive, "/book/src", "ocirun-0", true)
+            .unwrap();
+        assert_eq!(args[4..6], ["--network", "bridge"]);
+
+        let directive = Directive::parse("writable=true alpine touch out.txt");
+        let args = preprocessor
+            .run_args(&directive, "/book/src", "ocirun-0", true)
+            .unwrap();
+        assert_eq!(args[9], "/book/src:/book/src");
+
+        let preprocessor = OciRunConfig {
+            user: Some("image".into()),
+            runtime: Some("runsc".into()),
+            ..Default::default()
+        }
+        .create_preprocessor(".".into())
+        .unwrap();
+        let directive = Directive::parse("alpine ls");
+        let args = preprocessor
+            .run_args(&directive, "/book/src", "ocirun-0", true)
+            .unwrap();
+        assert_eq!(args[6..8], ["--runtime", "runsc"]);
+    }
+
+    #[test]
+    pub fn test_user_args() {
+        let preprocessor = |engine: &str, user: &str| {
+            OciRunConfig {
+                engine: Some(engine.into()),
+                user: Some(user.into()),
+                ..Default::default()
+            }
+            .create_preprocessor(".".into())
+            .unwrap()
+        };
+        let docker = preprocessor("docker", "host");
+        assert_eq!(docker.user_args(Some("1000:100")), ["--user", "1000:100"]);
+        assert!(docker.user_args(None).is_empty());
+        let podman = preprocessor("podman", "host");
+        assert_eq!(podman.user_args(Some("1000:100")), ["--userns", "keep-id"]);
+        let image = preprocessor("docker", "image");
+        assert!(image.user_args(Some("1000:100")).is_empty());
+        let explicit = preprocessor("docker", "nobody");
+        assert_eq!(explicit.user_args(Some("1000:100")), ["--user", "nobody"]);
+    }
 }
@@ -0,0 +1,195 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::report::FailureKind;
+use crate::snippet::with_stderr;
+pub use crate::utils::capture::LimitExceeded;
+use crate::utils::capture::{Captured, SPAWN_ERROR};
+use crate::utils::Redactor;
+
+// Durations are written in seconds, like in the reports
+pub(crate) fn as_secs<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+pub(crate) fn from_secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    Ok(Duration::from_secs_f64(f64::deserialize(deserializer)?))
+}
+
+// What docker and podman exit with when they fail themselves, rather than the command they run
+const ENGINE_ERROR_EXIT_CODE: i32 = 125;
+
+/// What a directive or a snippet printed, and how it ended.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExecutionOutcome {
+    pub stdout: String,
+    pub stderr: String,
+    /// `None` when the command was stopped by a signal
+    pub exit_code: Option<i32>,
+    #[serde(serialize_with = "as_secs", deserialize_with = "from_secs")]
+    pub duration: Duration,
+}
+
+impl ExecutionOutcome {
+    /// Succeeded with this output.
+    pub fn success(stdout: impl Into<String>) -> Self {
+        Self {
+            stdout: stdout.into(),
+            exit_code: Some(0),
+            ..Default::default()
+        }
+    }
+
+    /// Exited with this code, printing this error.
+    pub fn failure(stderr: impl Into<String>, exit_code: i32) -> Self {
+        Self {
+            stderr: stderr.into(),
+            exit_code: Some(exit_code),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    // Everything printed, with the error lines last, as the book shows failures
+    pub fn combined(&self) -> String {
+        with_stderr(self.stdout.clone(), &self.stderr)
+    }
+}
+
+/// Why a directive or a snippet did not succeed.
+#[derive(Debug, Clone, PartialEq, Error, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExecutionError {
+    /// It ran and exited with an error
+    #[error("failed with exit code {}", .0.exit_code.map_or("none".into(), |code| code.to_string()))]
+    Failed(ExecutionOutcome),
+    /// The engine failed to create its container, e.g. to pull the image
+    #[error("the container engine failed with exit code {}", .0.exit_code.map_or("none".into(), |code| code.to_string()))]
+    EngineFailed(ExecutionOutcome),
+    /// It was stopped for going over a limit
+    #[error("{}", .0.message())]
+    LimitExceeded(LimitExceeded),
+    /// The container engine could not be run
+    #[error("{}: {0}", SPAWN_ERROR)]
+    EngineUnavailable(String),
+}
+
+impl ExecutionError {
+    // What the book shows of the failure
+    pub fn output(&self) -> String {
+        match self {
+            Self::Failed(outcome) | Self::EngineFailed(outcome) => outcome.combined(),
+            error => format!("{error}\n"),
+        }
+    }
+
+    pub fn failure_kind(&self) -> FailureKind {
+        match self {
+            // What the command prints is never taken for an error of the engine
+            Self::Failed(outcome) if outcome.exit_code == Some(ENGINE_ERROR_EXIT_CODE) => {
+                FailureKind::classify(&outcome.stderr)
+            }
+            Self::Failed(_) => FailureKind::Execution,
+            Self::EngineFailed(outcome) => FailureKind::classify(&outcome.stderr),
+            Self::LimitExceeded(_) => FailureKind::Execution,
+            Self::EngineUnavailable(_) => FailureKind::EngineUnavailable,
+        }
+    }
+
+    pub fn outcome(&self) -> Option<&ExecutionOutcome> {
+        match self {
+            Self::Failed(outcome) | Self::EngineFailed(outcome) => Some(outcome),
+            _ => None,
+        }
+    }
+}
+
+/// The result of a directive or a snippet.
+pub type ExecutionResult = Result<ExecutionOutcome, ExecutionError>;
+
+// The result of a captured command, its output redacted as it is read
+pub(crate) fn from_captured(
+    captured: anyhow::Result<Captured>,
+    started: Instant,
+    redactor: &Redactor,
+) -> ExecutionResult {
+    let captured =
+        captured.map_err(|e| ExecutionError::EngineUnavailable(e.root_cause().to_string()))?;
+    if let Some(exceeded) = captured.exceeded {
+        return Err(ExecutionError::LimitExceeded(exceeded));
+    }
+    let text =
+        |bytes: &[u8]| redactor.redact(&String::from_utf8_lossy(bytes).replace("\r\n", "\n"));
+    let outcome = ExecutionOutcome {
+        stdout: text(&captured.stdout),
+        stderr: text(&captured.stderr),
+        exit_code: captured.status.code(),
+        duration: started.elapsed(),
+    };
+    match captured.status.success() {
+        true => Ok(outcome),
+        false => Err(ExecutionError::Failed(outcome)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExecutionError, ExecutionOutcome};
+    use crate::report::FailureKind;
+
+    #[test]
+    pub fn test_execution_error() {
+        assert_eq!(ExecutionOutcome::success("out\n").combined(), "out\n");
+        assert_eq!(ExecutionOutcome::failure("error", 1).combined(), "error\n");
+        let error = ExecutionError::Failed(ExecutionOutcome {
+            stdout: "out".into(),
+            ..ExecutionOutcome::failure("error\n", 2)
+        });
+        assert_eq!(error.to_string(), "failed with exit code 2");
+        assert_eq!(error.output(), "out\nerror\n");
+        assert_eq!(error.failure_kind(), FailureKind::Execution);
+
+        // Only the engine failing itself tells about the engine
+        let pull = "docker: Error response from daemon: manifest unknown\n";
+        assert_eq!(
+            ExecutionError::Failed(ExecutionOutcome::failure(pull, 1)).failure_kind(),
+            FailureKind::Execution
+        );
+        assert_eq!(
+            ExecutionError::Failed(ExecutionOutcome::failure(pull, 125)).failure_kind(),
+            FailureKind::ImagePull
+        );
+        let error = ExecutionError::EngineFailed(ExecutionOutcome::failure(pull, 1));
+        assert_eq!(error.failure_kind(), FailureKind::ImagePull);
+        assert_eq!(
+            error.to_string(),
+            "the container engine failed with exit code 1"
+        );
+        assert_eq!(error.output(), pull);
+
+        let error = ExecutionError::EngineUnavailable("No such file or directory".into());
+        assert_eq!(
+            error.output(),
+            "Fail to spawn the container engine: No such file or directory\n"
+        );
+        assert_eq!(error.failure_kind(), FailureKind::EngineUnavailable);
+
+        let json = serde_json::to_string(&error).unwrap();
+        assert_eq!(
+            json,
+            r#"{"engine-unavailable":"No such file or directory"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<ExecutionError>(&json).unwrap(),
+            error
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "mdbook")]
+use std::time::Duration;
+
+use indicatif::ProgressBar;
+#[cfg(feature = "mdbook")]
+use indicatif::{ProgressDrawTarget, ProgressStyle};
+
+#[cfg(feature = "mdbook")]
+const TEMPLATE: &str = "{spinner} [{bar:30}] {pos}/{len} chapters, {msg}";
+const MAX_COMMAND_LENGTH: usize = 60;
+
+/// Progress of a build drawn on stderr, only when it is a terminal.
+pub struct Progress {
+    bar: ProgressBar,
+    executed: AtomicUsize,
+    cached: AtomicUsize,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self {
+            bar: ProgressBar::hidden(),
+            executed: AtomicUsize::new(0),
+            cached: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Progress {
+    // Only books have chapters to count
+    #[cfg(feature = "mdbook")]
+    pub fn start(&self, chapters: usize) {
+        self.bar.set_draw_target(ProgressDrawTarget::stderr());
+        self.bar.set_style(
+            ProgressStyle::with_template(TEMPLATE)
+                .expect("Failed to init progress template")
+                .progress_chars("=> "),
+        );
+        self.bar.set_length(chapters as u64);
+        self.bar.enable_steady_tick(Duration::from_millis(100));
+        self.bar.set_message(self.counts());
+    }
+
+    pub fn running(&self, image: &str, command: &str) {
+        let mut command = command.split_whitespace().collect::<Vec<_>>().join(" ");
+        if command.chars().count() > MAX_COMMAND_LENGTH {
+            command = command.chars().take(MAX_COMMAND_LENGTH).collect::<String>() + "...";
+        }
+        self.bar
+            .set_message(format!("{}, running {} {}", self.counts(), image, command));
+    }
+
+    pub fn done(&self, cached: bool) {
+        match cached {
+            true => self.cached.fetch_add(1, Ordering::SeqCst),
+            false => self.executed.fetch_add(1, Ordering::SeqCst),
+        };
+        self.bar.set_message(self.counts());
+    }
+
+    #[cfg(feature = "mdbook")]
+    pub fn chapter_done(&self) {
+        self.bar.inc(1);
+    }
+
+    // Runs `print` with the bar cleared, so the printed lines are not drawn over
+    pub fn suspend<R>(&self, print: impl FnOnce() -> R) -> R {
+        self.bar.suspend(print)
+    }
+
+    #[cfg(feature = "mdbook")]
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+
+    fn counts(&self) -> String {
+        format!(
+            "{} executed, {} cached",
+            self.executed.load(Ordering::SeqCst),
+            self.cached.load(Ordering::SeqCst)
+        )
+    }
+}
@@ -0,0 +1,1041 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::ocirun::Severity;
+use crate::outcome::{as_secs, from_secs, ExecutionOutcome};
+use crate::utils::capture::SPAWN_ERROR;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecutionKind {
+    Directive,
+    Snippet,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheStatus {
+    /// The result was read from the cache
+    Hit,
+    /// The result was computed then cached
+    Miss,
+    /// The result is never cached, like the directives ones
+    Disabled,
+}
+
+/// Why an execution failed, each kind has its own exit code for `check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureKind {
+    /// The engine could not be run, or could not reach its daemon
+    EngineUnavailable,
+    /// The image could not be pulled
+    ImagePull,
+    /// The command or the snippet failed
+    Execution,
+    /// The output differs from the expected one
+    Assertion,
+}
+
+// What the engines print when they can not work at all
+const ENGINE_UNAVAILABLE_ERRORS: &[&str] = &[
+    SPAWN_ERROR,
+    "cannot connect to the docker daemon",
+    "cannot connect to podman",
+    "is the docker daemon running",
+];
+
+// What the engines print when an image is missing from the registry or out of reach
+const IMAGE_PULL_ERRORS: &[&str] = &[
+    "pull access denied",
+    "manifest unknown",
+    "not found: manifest",
+    "image not known",
+    "error pulling image",
+    "failed to resolve reference",
+    "repository does not exist",
+];
+
+impl FailureKind {
+    // Tells the failures of the engine from the ones of the commands by their error
+    pub fn classify(error: &str) -> Self {
+        let error = error.to_lowercase();
+        let matches = |patterns: &[&str]| {
+            patterns
+                .iter()
+                .any(|pattern| error.contains(&pattern.to_lowercase()))
+        };
+        if matches(ENGINE_UNAVAILABLE_ERRORS) {
+            Self::EngineUnavailable
+        } else if matches(IMAGE_PULL_ERRORS) {
+            Self::ImagePull
+        } else {
+            Self::Execution
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Execution => 1,
+            Self::EngineUnavailable => 4,
+            Self::ImagePull => 5,
+            Self::Assertion => 6,
+        }
+    }
+}
+
+fn as_rfc3339<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&humantime::format_rfc3339_millis(*time).to_string())
+}
+
+fn from_rfc3339<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+    humantime::parse_rfc3339_weak(&String::deserialize(deserializer)?)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Identifies the command run in an image, like in the reports.
+pub fn command_hash(image: &str, command: &str) -> String {
+    sha256::digest(format!("{image}:{command}"))
+}
+
+/// What happened when a directive or a snippet was run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Execution {
+    pub kind: ExecutionKind,
+    /// Chapter source path, relative to the book sources
+    pub chapter: Option<String>,
+    /// Line of the directive or snippet in the chapter
+    pub line: Option<usize>,
+    pub image: String,
+    pub command: String,
+    pub command_hash: String,
+    /// Where the result of a snippet is cached, see [`crate::snippet::CodeSnippet::cache_key`]
+    pub cache_key: Option<String>,
+    #[serde(serialize_with = "as_secs", deserialize_with = "from_secs")]
+    pub duration: Duration,
+    pub cache: CacheStatus,
+    pub exit_code: Option<i32>,
+    pub success: bool,
+    /// Output of a failed execution
+    pub error: Option<String>,
+    pub failure: Option<FailureKind>,
+    pub severity: Severity,
+    #[serde(serialize_with = "as_rfc3339", deserialize_with = "from_rfc3339")]
+    pub finished_at: SystemTime,
+    /// What the execution printed, and how it ended, when it was run
+    pub outcome: Option<ExecutionOutcome>,
+}
+
+impl Execution {
+    pub fn new(kind: ExecutionKind, image: &str, command: &str) -> Self {
+        Self {
+            kind,
+            chapter: None,
+            line: None,
+            image: image.to_string(),
+            command: command.to_string(),
+            command_hash: command_hash(image, command),
+            cache_key: None,
+            duration: Duration::ZERO,
+            cache: CacheStatus::Disabled,
+            exit_code: None,
+            success: false,
+            error: None,
+            failure: None,
+            severity: Severity::Error,
+            finished_at: SystemTime::now(),
+            outcome: None,
+        }
+    }
+
+    // Failures of `severity = "warn"` languages are only warnings
+    pub fn fails_build(&self) -> bool {
+        !self.success && self.severity == Severity::Error
+    }
+}
+
+// Outputs are cut in the log, the rendered book has them whole
+const LOG_OUTPUT_MAX_LINES: usize = 20;
+
+#[derive(Default)]
+struct Location {
+    chapter: Option<String>,
+    line: Option<usize>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.chapter, self.line) {
+            (Some(chapter), Some(line)) => write!(f, "{chapter}:{line}"),
+            (Some(chapter), None) => write!(f, "{chapter}"),
+            (None, Some(line)) => write!(f, "line {line}"),
+            (None, None) => write!(f, "ocirun"),
+        }
+    }
+}
+
+/// Every execution of a build, recorded as they happen.
+pub struct Report {
+    started: Instant,
+    location: Mutex<Location>,
+    executions: Mutex<Vec<Execution>>,
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            location: Mutex::new(Location::default()),
+            executions: Mutex::new(vec![]),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    summary: Summary,
+    executions: &'a [Execution],
+}
+
+#[derive(Deserialize)]
+struct JsonReportExecutions {
+    executions: Vec<Execution>,
+}
+
+impl Report {
+    // Executions recorded from now on happened in this chapter
+    pub fn enter_chapter(&self, chapter: Option<String>) {
+        *self.location.lock().unwrap() = Location {
+            chapter,
+            line: None,
+        };
+    }
+
+    // Executions recorded from now on happened at this line of the chapter
+    pub fn enter_line(&self, line: usize) {
+        self.location.lock().unwrap().line = Some(line);
+    }
+
+    // Where the executions happen now, as `chapter:line`
+    pub fn location(&self) -> String {
+        self.location.lock().unwrap().to_string()
+    }
+
+    // Sets where the execution happens, as it would be recorded now
+    pub fn locate(&self, execution: &mut Execution) {
+        let location = self.location.lock().unwrap();
+        execution.chapter = location.chapter.clone();
+        execution.line = location.line;
+    }
+
+    pub fn record(&self, mut execution: Execution) {
+        self.locate(&mut execution);
+        self.executions.lock().unwrap().push(execution);
+    }
+
+    // How many executions were recorded so far
+    pub fn recorded(&self) -> usize {
+        self.executions.lock().unwrap().len()
+    }
+
+    // Whether something was recorded after the first `count` executions, all successful
+    pub fn succeeded_since(&self, count: usize) -> bool {
+        let executions = self.executions.lock().unwrap();
+        let since = &executions[count.min(executions.len())..];
+        !since.is_empty() && since.iter().all(|execution| execution.success)
+    }
+
+    pub fn last(&self) -> Option<Execution> {
+        self.executions.lock().unwrap().last().cloned()
+    }
+
+    pub fn executions(&self) -> Vec<Execution> {
+        self.executions.lock().unwrap().clone()
+    }
+
+    // The exit code of the most fundamental failure, as an engine that is not
+    // available fails everything after it
+    pub fn exit_code(&self) -> i32 {
+        self.failures()
+            .iter()
+            .map(|execution| execution.failure.unwrap_or(FailureKind::Execution))
+            .min()
+            .map_or(0, |failure| failure.exit_code())
+    }
+
+    pub fn failures(&self) -> Vec<Execution> {
+        self.executions()
+            .into_iter()
+            .filter(Execution::fails_build)
+            .collect()
+    }
+
+    // The executions as `cargo test` lists its tests,
+    // for the `test` renderer of mdbook
+    pub fn test_results(&self) -> String {
+        let executions = self.executions();
+        let mut results = format!("\nrunning {} ocirun tests\n", executions.len());
+        for execution in &executions {
+            let status = match (execution.success, execution.severity) {
+                (true, _) => "ok",
+                (false, Severity::Warn) => "warning",
+                (false, Severity::Error) => "FAILED",
+            };
+            let chapter = execution.chapter.as_deref().unwrap_or("ocirun");
+            results.push_str(&format!(
+                "test {chapter} - {} ... {status}\n",
+                execution.junit_name()
+            ));
+        }
+        let count =
+            |predicate: fn(&&Execution) -> bool| executions.iter().filter(predicate).count();
+        let failures = count(|execution| execution.fails_build());
+        let warnings = count(|execution| !execution.success && !execution.fails_build());
+        results.push_str(&format!(
+            "\ntest result: {}. {} passed; {failures} failed",
+            if failures == 0 { "ok" } else { "FAILED" },
+            count(|execution| execution.success),
+        ));
+        if warnings > 0 {
+            results.push_str(&format!("; {warnings} warned"));
+        }
+        results.push('\n');
+        results
+    }
+
+    // One summary per chapter, in the order they were built
+    pub fn chapter_summaries(&self) -> Vec<ChapterSummary> {
+        let mut chapters: Vec<ChapterSummary> = vec![];
+        for execution in self.executions.lock().unwrap().iter() {
+            let chapter = execution.chapter.as_deref().unwrap_or("ocirun");
+            let index = match chapters
+                .iter()
+                .position(|summary| summary.chapter == chapter)
+            {
+                Some(index) => index,
+                None => {
+                    chapters.push(ChapterSummary {
+                        chapter: chapter.to_string(),
+                        ..Default::default()
+                    });
+                    chapters.len() - 1
+                }
+            };
+            let summary = &mut chapters[index];
+            match (execution.success, execution.cache) {
+                (true, CacheStatus::Hit) => summary.cached += 1,
+                (true, _) => summary.passed += 1,
+                (false, _) if execution.fails_build() => summary.failed += 1,
+                (false, _) => summary.warned += 1,
+            }
+        }
+        chapters
+    }
+
+    pub fn summary(&self) -> Summary {
+        let executions = self.executions.lock().unwrap();
+        let count = |predicate: &dyn Fn(&Execution) -> bool| {
+            executions
+                .iter()
+                .filter(|&execution| predicate(execution))
+                .count()
+        };
+        Summary {
+            directives: count(&|execution| execution.kind == ExecutionKind::Directive),
+            snippets: count(&|execution| execution.kind == ExecutionKind::Snippet),
+            cache_hits: count(&|execution| execution.cache == CacheStatus::Hit),
+            cache_misses: count(&|execution| execution.cache == CacheStatus::Miss),
+            // Warnings do not fail the build, so they are not failures of the summary
+            failures: count(&|execution| execution.fails_build()),
+            container_time: executions
+                .iter()
+                .filter(|execution| execution.cache != CacheStatus::Hit)
+                .map(|execution| execution.duration)
+                .sum(),
+            wall_time: self.started.elapsed(),
+        }
+    }
+
+    // A block per execution, starting with when it started
+    pub fn write_log(&self, path: &Path) -> Result<()> {
+        let log: String = self.executions().iter().map(Execution::log_entry).collect();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, log)
+            .with_context(|| format!("Could not write the ocirun log to {}", path.display()))
+    }
+
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        let executions = self.executions();
+        let report = JsonReport {
+            summary: self.summary(),
+            executions: &executions,
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(&report)?)
+            .with_context(|| format!("Could not write the ocirun report to {}", path.display()))
+    }
+
+    /// Reads back the executions of a JSON report, e.g. to compare two builds.
+    pub fn read_json(path: &Path) -> Result<Vec<Execution>> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Could not read the ocirun report {}", path.display()))?;
+        let report: JsonReportExecutions = serde_json::from_str(&content)
+            .with_context(|| format!("Could not parse the ocirun report {}", path.display()))?;
+        Ok(report.executions)
+    }
+
+    pub fn github_annotations(&self, src_dir: &Path) -> Vec<String> {
+        self.executions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|execution| execution.github_annotation(src_dir))
+            .collect()
+    }
+
+    pub fn write_junit(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, junit(&self.executions(), &self.summary())).with_context(|| {
+            format!(
+                "Could not write the ocirun JUnit report to {}",
+                path.display()
+            )
+        })
+    }
+}
+
+fn xml_escape(str: &str) -> String {
+    str.chars()
+        .filter(|&c| !c.is_control() || c == '\n' || c == '\t')
+        .fold(String::new(), |mut escaped, c| {
+            match c {
+                '&' => escaped.push_str("&amp;"),
+                '<' => escaped.push_str("&lt;"),
+                '>' => escaped.push_str("&gt;"),
+                '"' => escaped.push_str("&quot;"),
+                '\'' => escaped.push_str("&apos;"),
+                c => escaped.push(c),
+            }
+            escaped
+        })
+}
+
+impl Execution {
+    fn log_entry(&self) -> String {
+        let started = self
+            .finished_at
+            .checked_sub(self.duration)
+            .unwrap_or(self.finished_at);
+        let kind = match self.kind {
+            ExecutionKind::Directive => "directive",
+            ExecutionKind::Snippet => "snippet",
+        };
+        let cache = match self.cache {
+            CacheStatus::Hit => "hit",
+            CacheStatus::Miss => "miss",
+            CacheStatus::Disabled => "disabled",
+        };
+        let mut entry = format!(
+            "{} {}:{} {kind} in image `{}`: {}\n  {}, {:.3}s, cache {cache}, exit code {}\n",
+            humantime::format_rfc3339_millis(started),
+            self.chapter.as_deref().unwrap_or("ocirun"),
+            self.line.map(|line| line.to_string()).unwrap_or_default(),
+            self.image,
+            self.command.trim(),
+            if self.success { "succeeded" } else { "failed" },
+            self.duration.as_secs_f64(),
+            self.exit_code
+                .map(|code| code.to_string())
+                .unwrap_or("none".into()),
+        );
+        let output = self.outcome.as_ref().map(|outcome| outcome.stdout.clone());
+        for (name, text) in [("output", &output), ("error", &self.error)] {
+            let Some(text) = text.as_deref().filter(|text| !text.trim().is_empty()) else {
+                continue;
+            };
+            entry.push_str(&format!("  {name}:\n"));
+            let lines: Vec<&str> = text.lines().collect();
+            for line in lines.iter().take(LOG_OUTPUT_MAX_LINES) {
+                entry.push_str(&format!("  | {line}\n"));
+            }
+            if lines.len() > LOG_OUTPUT_MAX_LINES {
+                entry.push_str(&format!(
+                    "  | ... {} more lines\n",
+                    lines.len() - LOG_OUTPUT_MAX_LINES
+                ));
+            }
+        }
+        entry
+    }
+
+    fn junit_name(&self) -> String {
+        let kind = match self.kind {
+            ExecutionKind::Directive => "directive",
+            ExecutionKind::Snippet => "snippet",
+        };
+        let command = self
+            .command
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        match self.line {
+            Some(line) => format!("{kind} at line {line}: {} {command}", self.image),
+            None => format!("{kind}: {} {command}", self.image),
+        }
+    }
+}
+
+// One test suite per chapter, in the order they were built,
+// with a test case per execution
+fn junit(executions: &[Execution], summary: &Summary) -> String {
+    let mut chapters: Vec<(String, Vec<&Execution>)> = vec![];
+    for execution in executions {
+        let chapter = execution.chapter.clone().unwrap_or_default();
+        match chapters.iter_mut().find(|(name, _)| *name == chapter) {
+            Some((_, executions)) => executions.push(execution),
+            None => chapters.push((chapter, vec![execution])),
+        }
+    }
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuites name=\"ocirun\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+        executions.len(),
+        summary.failures,
+        summary.wall_time.as_secs_f64()
+    ));
+    for (chapter, executions) in chapters {
+        let chapter = xml_escape(&chapter);
+        xml.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+            chapter,
+            executions.len(),
+            executions
+                .iter()
+                .filter(|execution| execution.fails_build())
+                .count(),
+            executions
+                .iter()
+                .map(|execution| execution.duration)
+                .sum::<Duration>()
+                .as_secs_f64()
+        ));
+        for execution in executions {
+            xml.push_str(&format!(
+                "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+                chapter,
+                xml_escape(&execution.junit_name()),
+                execution.duration.as_secs_f64()
+            ));
+            if execution.success {
+                xml.push_str("/>\n");
+                continue;
+            }
+            let message = match execution.exit_code {
+                Some(code) => format!("exited with code {code}"),
+                None => "failed".to_string(),
+            };
+            let error = xml_escape(execution.error.as_deref().unwrap_or_default());
+            // Warnings are only reported, CI would fail on a `<failure>`
+            match execution.fails_build() {
+                true => xml.push_str(&format!(
+                    ">\n      <failure message=\"{message}\">{error}</failure>\n    </testcase>\n"
+                )),
+                false => xml.push_str(&format!(
+                    ">\n      <system-err>warning: {message}\n{error}</system-err>\n    </testcase>\n"
+                )),
+            }
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+impl fmt::Display for Execution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            ExecutionKind::Directive => "directive",
+            ExecutionKind::Snippet => "snippet",
+        };
+        if let Some(chapter) = &self.chapter {
+            write!(f, "{chapter}:")?;
+            if let Some(line) = self.line {
+                write!(f, "{line}:")?;
+            }
+            write!(f, " ")?;
+        }
+        write!(f, "{kind} in image `{}`", self.image)?;
+        match self.success {
+            true => write!(f, " succeeded"),
+            false => {
+                write!(f, " failed")?;
+                match self.error.as_deref().and_then(|error| error.lines().next()) {
+                    Some(line) if !line.is_empty() => write!(f, ": {line}"),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+// Data and property escaping of the GitHub workflow commands
+fn github_escape(str: &str, property: bool) -> String {
+    let escaped = str
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A");
+    match property {
+        true => escaped.replace(':', "%3A").replace(',', "%2C"),
+        false => escaped,
+    }
+}
+
+impl Execution {
+    /// `::error` workflow command, or `::warning` one, annotating the chapter source where this execution failed,
+    /// `src_dir` being the path of the book sources as seen from the repository root.
+    pub fn github_annotation(&self, src_dir: &Path) -> Option<String> {
+        if self.success {
+            return None;
+        }
+        let mut properties = vec![];
+        if let Some(chapter) = &self.chapter {
+            let file = src_dir.join(chapter).to_string_lossy().replace('\\', "/");
+            properties.push(format!("file={}", github_escape(&file, true)));
+        }
+        if let Some(line) = self.line {
+            properties.push(format!("line={line}"));
+        }
+        let kind = match self.kind {
+            ExecutionKind::Directive => "directive",
+            ExecutionKind::Snippet => "snippet",
+        };
+        let mut message = format!("ocirun {kind} failed in image `{}`", self.image);
+        if let Some(error) = self.error.as_deref().map(str::trim_end) {
+            if !error.is_empty() {
+                message = format!("{message}:\n{error}");
+            }
+        }
+        Some(format!(
+            "::{} {}::{}",
+            match self.severity {
+                Severity::Error => "error",
+                Severity::Warn => "warning",
+            },
+            properties.join(","),
+            github_escape(&message, false)
+        ))
+    }
+}
+
+/// Totals of a build, printed once it is done.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    pub directives: usize,
+    pub snippets: usize,
+    pub cache_hits: usize,
+    pub cache_misses: usize,
+    pub failures: usize,
+    #[serde(serialize_with = "as_secs", deserialize_with = "from_secs")]
+    pub container_time: Duration,
+    #[serde(serialize_with = "as_secs", deserialize_with = "from_secs")]
+    pub wall_time: Duration,
+}
+
+/// Counts of the executions of a chapter, printed once the build is done.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChapterSummary {
+    pub chapter: String,
+    pub passed: usize,
+    pub cached: usize,
+    pub failed: usize,
+    /// Failures of `severity = "warn"` languages
+    pub warned: usize,
+}
+
+// ANSI colors of the chapter summaries
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+impl ChapterSummary {
+    // Red when something failed, yellow when something only warned, green otherwise
+    pub fn colored(&self) -> String {
+        let color = match (self.failed, self.warned) {
+            (0, 0) => GREEN,
+            (0, _) => YELLOW,
+            _ => RED,
+        };
+        format!("{color}{self}{RESET}")
+    }
+}
+
+impl fmt::Display for ChapterSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "  {}: {} passed, {} cached, {} failed",
+            self.chapter, self.passed, self.cached, self.failed
+        )?;
+        if self.warned > 0 {
+            write!(f, ", {} warned", self.warned)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ocirun: {} directives, {} snippets ({} cache hits, {} cache misses), {} failures, \
+             {:.1}s in containers, {:.1}s in total",
+            self.directives,
+            self.snippets,
+            self.cache_hits,
+            self.cache_misses,
+            self.failures,
+            self.container_time.as_secs_f64(),
+            self.wall_time.as_secs_f64()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{
+        junit, CacheStatus, ChapterSummary, Execution, ExecutionKind, FailureKind, Report,
+    };
+    use crate::ocirun::Severity;
+    use crate::outcome::ExecutionOutcome;
+
+    fn execution(kind: ExecutionKind, cache: CacheStatus, success: bool) -> Execution {
+        Execution {
+            duration: Duration::from_secs(2),
+            cache,
+            success,
+            ..Execution::new(kind, "alpine", "ls")
+        }
+    }
+
+    #[test]
+    pub fn test_summary() {
+        let report = Report::default();
+        report.record(execution(
+            ExecutionKind::Directive,
+            CacheStatus::Disabled,
+            true,
+        ));
+        report.record(execution(
+            ExecutionKind::Directive,
+            CacheStatus::Disabled,
+            false,
+        ));
+        report.record(execution(ExecutionKind::Snippet, CacheStatus::Hit, true));
+        report.record(execution(ExecutionKind::Snippet, CacheStatus::Miss, true));
+        report.record(Execution {
+            severity: Severity::Warn,
+            ..execution(ExecutionKind::Snippet, CacheStatus::Miss, false)
+        });
+
+        let summary = report.summary();
+        assert_eq!(summary.directives, 2);
+        assert_eq!(summary.snippets, 3);
+        assert_eq!(summary.cache_hits, 1);
+        assert_eq!(summary.cache_misses, 2);
+        assert_eq!(summary.failures, 1);
+        assert_eq!(summary.container_time, Duration::from_secs(8));
+    }
+
+    #[test]
+    pub fn test_display_failures() {
+        let report = Report::default();
+        report.enter_chapter(Some("intro.md".into()));
+        report.enter_line(3);
+        report.record(execution(
+            ExecutionKind::Directive,
+            CacheStatus::Disabled,
+            true,
+        ));
+        report.record(Execution {
+            error: Some("error[E0425]: cannot find value `x`\n --> source:2:5\n".into()),
+            ..execution(ExecutionKind::Snippet, CacheStatus::Miss, false)
+        });
+
+        let failures: Vec<String> = report.failures().iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            failures,
+            vec![
+                "intro.md:3: snippet in image `alpine` failed: error[E0425]: cannot find value `x`"
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_write_json() {
+        let report = Report::default();
+        report.enter_chapter(Some("intro.md".into()));
+        report.enter_line(3);
+        report.record(Execution {
+            exit_code: Some(0),
+            outcome: Some(ExecutionOutcome::success("README.md\n")),
+            ..execution(ExecutionKind::Directive, CacheStatus::Disabled, true)
+        });
+        // The report keeps the milliseconds of the times
+        let recorded = Execution {
+            finished_at: UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            ..report.last().unwrap()
+        };
+        *report.executions.lock().unwrap() = vec![recorded.clone()];
+
+        let path = std::env::temp_dir().join("ocirun-test-report.json");
+        report.write_json(&path).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(Report::read_json(&path).unwrap(), vec![recorded]);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(json["summary"]["directives"], 1);
+        let execution = &json["executions"][0];
+        assert_eq!(execution["kind"], "directive");
+        assert_eq!(execution["chapter"], "intro.md");
+        assert_eq!(execution["line"], 3);
+        assert_eq!(execution["image"], "alpine");
+        assert_eq!(execution["command_hash"], sha256::digest("alpine:ls"));
+        assert_eq!(execution["duration"], 2.0);
+        assert_eq!(execution["cache"], "disabled");
+        assert_eq!(execution["exit_code"], 0);
+        assert_eq!(execution["finished_at"], "2023-11-14T22:13:20.123Z");
+        assert_eq!(execution["outcome"]["stdout"], "README.md\n");
+    }
+
+    #[test]
+    pub fn test_junit() {
+        let report = Report::default();
+        report.enter_chapter(Some("intro.md".into()));
+        report.enter_line(3);
+        report.record(execution(
+            ExecutionKind::Directive,
+            CacheStatus::Disabled,
+            true,
+        ));
+        report.enter_chapter(Some("a&b.md".into()));
+        report.enter_line(7);
+        report.record(Execution {
+            exit_code: Some(1),
+            error: Some("error: <main>\n".into()),
+            ..execution(ExecutionKind::Snippet, CacheStatus::Miss, false)
+        });
+        report.enter_line(9);
+        report.record(Execution {
+            exit_code: Some(2),
+            error: Some("unstable\n".into()),
+            severity: Severity::Warn,
+            ..execution(ExecutionKind::Snippet, CacheStatus::Miss, false)
+        });
+
+        let xml = junit(&report.executions(), &report.summary());
+        assert!(xml.contains("<testsuites name=\"ocirun\" tests=\"3\" failures=\"1\""));
+        assert!(xml.contains("<testsuite name=\"a&amp;b.md\" tests=\"2\" failures=\"1\""));
+        assert!(xml.contains(
+            "<testcase classname=\"a&amp;b.md\" name=\"snippet at line 9: alpine ls\" time=\"2.000\">\n      \
+             <system-err>warning: exited with code 2\nunstable\n</system-err>"
+        ));
+        assert_eq!(xml.matches("<failure").count(), 1);
+        assert!(xml.contains(
+            "<testsuite name=\"intro.md\" tests=\"1\" failures=\"0\" time=\"2.000\">\n    \
+             <testcase classname=\"intro.md\" name=\"directive at line 3: alpine ls\" time=\"2.000\"/>"
+        ));
+        assert!(xml.contains(
+            "<testcase classname=\"a&amp;b.md\" name=\"snippet at line 7: alpine ls\" time=\"2.000\">\n      \
+             <failure message=\"exited with code 1\">error: &lt;main&gt;\n</failure>"
+        ));
+    }
+
+    #[test]
+    pub fn test_test_results() {
+        let report = Report::default();
+        report.enter_chapter(Some("intro.md".into()));
+        report.enter_line(3);
+        report.record(execution(
+            ExecutionKind::Directive,
+            CacheStatus::Disabled,
+            true,
+        ));
+        report.enter_line(7);
+        report.record(execution(ExecutionKind::Snippet, CacheStatus::Miss, false));
+        report.enter_line(11);
+        report.record(Execution {
+            severity: Severity::Warn,
+            ..execution(ExecutionKind::Snippet, CacheStatus::Miss, false)
+        });
+        assert_eq!(
+            report.test_results(),
+            "\nrunning 3 ocirun tests\n\
+             test intro.md - directive at line 3: alpine ls ... ok\n\
+             test intro.md - snippet at line 7: alpine ls ... FAILED\n\
+             test intro.md - snippet at line 11: alpine ls ... warning\n\
+             \ntest result: FAILED. 1 passed; 1 failed; 1 warned\n"
+        );
+        assert_eq!(report.failures().len(), 1);
+    }
+
+    #[test]
+    pub fn test_log_entry() {
+        let output: String = (1..=25).map(|line| format!("line {line}\n")).collect();
+        let execution = Execution {
+            chapter: Some("intro.md".into()),
+            line: Some(3),
+            exit_code: Some(0),
+            finished_at: UNIX_EPOCH + Duration::from_secs(1_700_000_002),
+            outcome: Some(ExecutionOutcome::success(output)),
+            ..execution(ExecutionKind::Directive, CacheStatus::Disabled, true)
+        };
+        let entry = execution.log_entry();
+        assert!(entry.starts_with(
+            "2023-11-14T22:13:20.000Z intro.md:3 directive in image `alpine`: ls\n  \
+             succeeded, 2.000s, cache disabled, exit code 0\n  output:\n  | line 1\n"
+        ));
+        assert!(entry.ends_with("  | line 20\n  | ... 5 more lines\n"));
+    }
+
+    #[test]
+    pub fn test_github_annotations() {
+        let report = Report::default();
+        report.enter_chapter(Some("nested/intro.md".into()));
+        report.enter_line(12);
+        report.record(execution(
+            ExecutionKind::Directive,
+            CacheStatus::Disabled,
+            true,
+        ));
+        report.record(Execution {
+            error: Some("100% broken\nsee above\n".into()),
+            ..execution(ExecutionKind::Snippet, CacheStatus::Miss, false)
+        });
+
+        assert_eq!(
+            report.github_annotations(Path::new("docs/src")),
+            vec![
+                "::error file=docs/src/nested/intro.md,line=12::ocirun snippet failed \
+                 in image `alpine`:%0A100%25 broken%0Asee above"
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_failure_exit_code() {
+        assert_eq!(
+            FailureKind::classify(
+                "Cannot connect to the Docker daemon at unix:///var/run/docker.sock. \
+                 Is the docker daemon running?"
+            ),
+            FailureKind::EngineUnavailable
+        );
+        assert_eq!(
+            FailureKind::classify("Fail to spawn the container engine: No such file"),
+            FailureKind::EngineUnavailable
+        );
+        assert_eq!(
+            FailureKind::classify(
+                "Unable to find image 'nope:latest' locally\n\
+                 docker: Error response from daemon: pull access denied for nope"
+            ),
+            FailureKind::ImagePull
+        );
+        assert_eq!(
+            FailureKind::classify("ls: cannot access 'nope': No such file or directory"),
+            FailureKind::Execution
+        );
+
+        let report = Report::default();
+        assert_eq!(report.exit_code(), 0);
+        report.record(execution(ExecutionKind::Snippet, CacheStatus::Miss, true));
+        assert_eq!(report.exit_code(), 0);
+        report.record(Execution {
+            failure: Some(FailureKind::Assertion),
+            ..execution(ExecutionKind::Snippet, CacheStatus::Miss, false)
+        });
+        assert_eq!(report.exit_code(), 6);
+        report.record(execution(
+            ExecutionKind::Directive,
+            CacheStatus::Disabled,
+            false,
+        ));
+        assert_eq!(report.exit_code(), 1);
+        report.record(Execution {
+            failure: Some(FailureKind::ImagePull),
+            ..execution(ExecutionKind::Directive, CacheStatus::Disabled, false)
+        });
+        assert_eq!(report.exit_code(), 5);
+    }
+
+    #[test]
+    pub fn test_chapter_summaries() {
+        let report = Report::default();
+        report.enter_chapter(Some("intro.md".into()));
+        report.record(execution(ExecutionKind::Snippet, CacheStatus::Hit, true));
+        report.record(execution(ExecutionKind::Snippet, CacheStatus::Miss, true));
+        report.enter_chapter(Some("usage.md".into()));
+        report.record(execution(
+            ExecutionKind::Directive,
+            CacheStatus::Disabled,
+            false,
+        ));
+        report.record(Execution {
+            severity: Severity::Warn,
+            ..execution(ExecutionKind::Snippet, CacheStatus::Miss, false)
+        });
+        report.enter_chapter(Some("intro.md".into()));
+        report.record(execution(
+            ExecutionKind::Directive,
+            CacheStatus::Disabled,
+            true,
+        ));
+
+        let summaries = report.chapter_summaries();
+        assert_eq!(
+            summaries,
+            vec![
+                ChapterSummary {
+                    chapter: "intro.md".into(),
+                    passed: 2,
+                    cached: 1,
+                    failed: 0,
+                    warned: 0,
+                },
+                ChapterSummary {
+                    chapter: "usage.md".into(),
+                    passed: 0,
+                    cached: 0,
+                    failed: 1,
+                    warned: 1,
+                },
+            ]
+        );
+        assert_eq!(
+            summaries[0].colored(),
+            "\x1b[32m  intro.md: 2 passed, 1 cached, 0 failed\x1b[0m"
+        );
+        assert_eq!(
+            summaries[1].colored(),
+            "\x1b[31m  usage.md: 0 passed, 0 cached, 1 failed, 1 warned\x1b[0m"
+        );
+    }
+}
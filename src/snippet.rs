@@ -1,15 +1,18 @@
 use lazy_static::lazy_static;
 use std::{
     env::temp_dir,
+    ffi::OsStr,
     fs::File,
-    io::Write,
+    io::{self, Write},
     ops::Range,
     path::{Path, PathBuf},
-    process::{Command, Stdio},
+    process::{Command, Output, Stdio},
+    time::Instant,
 };
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
 
 lazy_static! {
     static ref OCIRUN_SNIPPET: Regex = RegexBuilder::new(r"```(?P<flags>.+)?")
@@ -19,15 +22,30 @@ lazy_static! {
         .expect("Failed to init regex for finding snippets pattern");
 }
 
-use crate::{ocirun::LangConfig, utils::format_whitespace, OciRun};
+use crate::{
+    allowlist::snippet_digest,
+    ocirun::{
+        echo_command, invocation, kill_container, LangConfig, LogLevel, Mode, ResourceLimits,
+        SecurityOptions, NOT_EXECUTED, SKIPPED,
+    },
+    outcome::{from_captured, ExecutionError, ExecutionOutcome, ExecutionResult},
+    report::{CacheStatus, Execution, ExecutionKind, FailureKind},
+    utils::{capture, format_whitespace, CaptureLimits, Redactor, Secrets},
+    OciRun,
+};
 
-const SUCCESS_PATH: &str = "success.txt";
-const ERROR_PATH: &str = "error.txt";
+const OUTCOME_PATH: &str = "outcome.json";
 
-#[derive(Hash, Eq, PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub image: String,
     pub command: Vec<String>,
+    /// Network of the container, the default one of the engine when unset
+    pub network: Option<String>,
+    pub limits: ResourceLimits,
+    pub security: SecurityOptions,
+    /// OCI runtime of the container, the default one of the engine when unset
+    pub runtime: Option<String>,
 }
 
 impl From<&LangConfig> for Config {
@@ -35,6 +53,10 @@ impl From<&LangConfig> for Config {
         Config {
             image: value.image.clone(),
             command: value.command.clone(),
+            network: value.network.clone(),
+            limits: value.limits.clone(),
+            security: SecurityOptions::default(),
+            runtime: None,
         }
     }
 }
@@ -45,7 +67,7 @@ pub enum Source {
 }
 
 impl Source {
-    fn get_content(&self) -> String {
+    pub fn get_content(&self) -> String {
         match self {
             Self::String(content) => content.clone(),
             Self::File(file) => std::fs::read_to_string(file).unwrap(),
@@ -57,25 +79,65 @@ impl Source {
     }
 
     fn get_path(&self) -> PathBuf {
+        let path = self.path();
+        if let Self::String(content) = self {
+            std::fs::write(path.clone(), content).unwrap();
+        }
+        path
+    }
+
+    // Where the source is copied from, without writing it
+    fn path(&self) -> PathBuf {
         match self {
-            Self::String(content) => {
-                let path = temp_dir().join(self.get_digest());
-                std::fs::write(path.clone(), content).unwrap();
-                path
-            }
+            Self::String(_) => temp_dir().join(self.get_digest()),
             Self::File(file) => Path::new(file).to_path_buf(),
         }
     }
 }
 
 pub struct CodeSnippet {
+    /// Prefix of the output lines when they are streamed
+    pub id: Option<String>,
     pub source: Source,
     pub input: Option<Source>,
     pub expected: Option<Result<Source, Source>>,
     pub config: Config,
 }
 
-struct CodeSnippetCache {
+impl CodeSnippet {
+    /// Identifies the result of the snippet: how it is run, its source and its input.
+    /// The secrets are left out, the outputs are redacted before being cached.
+    pub fn cache_key(&self) -> String {
+        let Config {
+            image,
+            command,
+            network,
+            limits,
+            security,
+            runtime,
+        } = &self.config;
+        let settings = serde_json::to_string(&(network, limits, security, runtime))
+            .expect("Failed to serialize the settings of a snippet");
+        let config = sha256::digest(format!("{image}:{}:{settings}", command.join(" ")));
+        let mut key = format!("{config}/{}", self.source.get_digest());
+        if let Some(input) = &self.input {
+            key = format!("{key}/{}", input.get_digest());
+        }
+        key
+    }
+}
+
+/// Where the results of the snippets are kept from a build to the next.
+pub trait SnippetCache: Send + Sync {
+    fn get(&self, snippet: &CodeSnippet) -> Option<ExecutionResult>;
+
+    fn add(&self, snippet: &CodeSnippet, result: &ExecutionResult);
+
+    fn invalidate(&self, snippet: &CodeSnippet);
+}
+
+/// Keeps the results in files, under `~/.mdbook/ocirun/` by default.
+pub struct CodeSnippetCache {
     pub path: String,
 }
 
@@ -88,7 +150,7 @@ impl Default for CodeSnippetCache {
 }
 
 impl CodeSnippetCache {
-    fn new(path: String) -> Self {
+    pub fn new(path: String) -> Self {
         let cache = Path::new(path.as_str());
         if !cache.is_dir() {
             std::fs::create_dir_all(&path).unwrap();
@@ -110,96 +172,141 @@ impl CodeSnippetCache {
     }
 
     fn as_cached_path(&self, snippet: &CodeSnippet) -> PathBuf {
-        let config_path = sha256::digest(format!(
-            "{}:{}",
-            snippet.config.image,
-            snippet.config.command.join(" ")
-        ));
-        let source_hash = snippet.source.get_digest();
-        let mut cache_path = Path::new(self.path.as_str())
-            .join(config_path)
-            .join(source_hash);
-        if let Some(input) = &snippet.input {
-            let input_hash = input.get_digest();
-            cache_path = cache_path.join(input_hash);
-        }
-        cache_path
+        Path::new(self.path.as_str()).join(snippet.cache_key())
     }
+}
 
-    fn get(&self, snippet: &CodeSnippet) -> Option<Result<String, String>> {
-        let cache_path = self.as_cached_path(snippet);
-        if !cache_path.is_dir() {
-            return None;
-        }
-        let success_output = cache_path.join(Path::new(SUCCESS_PATH));
-        if success_output.exists() {
-            let content = std::fs::read_to_string(success_output).unwrap();
-            return Some(Ok(content));
-        }
-        let error_output = cache_path.join(Path::new(ERROR_PATH));
-        if error_output.exists() {
-            let content = std::fs::read_to_string(error_output).unwrap();
-            return Some(Err(content));
-        }
-        None
+impl SnippetCache for CodeSnippetCache {
+    // Results cached by older versions, as plain text, are run again
+    fn get(&self, snippet: &CodeSnippet) -> Option<ExecutionResult> {
+        let outcome_path = self.as_cached_path(snippet).join(OUTCOME_PATH);
+        let content = std::fs::read_to_string(outcome_path).ok()?;
+        serde_json::from_str(&content).ok()
     }
 
-    fn add(&self, snippet: &CodeSnippet, result: &Result<String, String>) {
+    fn add(&self, snippet: &CodeSnippet, result: &ExecutionResult) {
         let cache_path = self.as_cached_path(snippet);
-        let error_path = cache_path.join(ERROR_PATH);
-        let success_path = cache_path.join(SUCCESS_PATH);
-        std::fs::create_dir_all(cache_path).unwrap();
-        let (file, content) = match result {
-            Ok(content) => (File::create(success_path), content),
-            Err(content) => (File::create(error_path), content),
-        };
-        file.unwrap().write_all(content.as_bytes()).unwrap();
+        std::fs::create_dir_all(&cache_path).unwrap();
+        let content = serde_json::to_string(result).unwrap();
+        File::create(cache_path.join(OUTCOME_PATH))
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+    }
+
+    fn invalidate(&self, snippet: &CodeSnippet) {
+        let _ = std::fs::remove_file(self.as_cached_path(snippet).join(OUTCOME_PATH));
     }
 }
 
-pub trait SnippetRunner {
-    fn run(&self, snippet: &CodeSnippet) -> Result<String, String>;
+pub trait SnippetRunner: Send + Sync {
+    fn run(&self, snippet: &CodeSnippet) -> ExecutionResult;
+
+    // Whether running the snippet would only read a previous result
+    fn is_cached(&self, _snippet: &CodeSnippet) -> bool {
+        false
+    }
 }
 
 pub struct OciSnippetRunner {
     pub engine: String,
+    pub limits: CaptureLimits,
+    pub redactor: Redactor,
+    pub secrets: Secrets,
+    pub log_level: LogLevel,
+    pub stream_output: bool,
 }
 
 impl Default for OciSnippetRunner {
     fn default() -> Self {
-        Self {
-            engine: "docker".into(),
-        }
+        Self::new("docker".into())
     }
 }
 
 impl OciSnippetRunner {
     pub fn new(engine: String) -> Self {
-        Self { engine }
+        Self {
+            engine,
+            limits: CaptureLimits::default(),
+            redactor: Redactor::default(),
+            secrets: Secrets::default(),
+            log_level: LogLevel::default(),
+            stream_output: false,
+        }
+    }
+
+    pub fn with_redactor(mut self, redactor: Redactor) -> Self {
+        self.redactor = redactor;
+        self
+    }
+
+    pub fn with_secrets(mut self, secrets: Secrets) -> Self {
+        self.secrets = secrets;
+        self
+    }
+
+    pub fn with_log_level(mut self, log_level: LogLevel) -> Self {
+        self.log_level = log_level;
+        self
+    }
+
+    pub fn with_stream_output(mut self, stream_output: bool) -> Self {
+        self.stream_output = stream_output;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: CaptureLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // Runs an engine command to completion, without any input
+    fn output<S: AsRef<OsStr>>(&self, args: impl IntoIterator<Item = S>) -> io::Result<Output> {
+        let mut command = Command::new(self.engine.as_str());
+        command.stdin(Stdio::null()).args(args);
+        self.secrets.apply(&mut command);
+        echo_command(self.log_level, &command);
+        command.output()
     }
 
     pub fn cached(self) -> CachedRunner<Self> {
+        self.cached_with(CodeSnippetCache::default())
+    }
+
+    pub fn cached_with(self, cache: impl SnippetCache + 'static) -> CachedRunner<Self> {
         CachedRunner {
-            cache: CodeSnippetCache::default(),
+            cache: Box::new(cache),
             runner: self,
         }
     }
 }
 
 pub struct CachedRunner<R: SnippetRunner> {
-    cache: CodeSnippetCache,
+    cache: Box<dyn SnippetCache>,
     runner: R,
 }
 
 impl<R: SnippetRunner> SnippetRunner for CachedRunner<R> {
-    fn run(&self, snippet: &CodeSnippet) -> Result<String, String> {
+    fn run(&self, snippet: &CodeSnippet) -> ExecutionResult {
         if let Some(result) = self.cache.get(snippet) {
             return result;
         }
         let result = self.runner.run(snippet);
-        self.cache.add(snippet, &result);
+        // The engine may work again next time, and the limits are not part of the key
+        let cacheable = match &result {
+            Err(ExecutionError::LimitExceeded(_) | ExecutionError::EngineFailed(_)) => false,
+            Err(error) => error.failure_kind() == FailureKind::Execution,
+            Ok(_) => true,
+        };
+        if cacheable {
+            self.cache.add(snippet, &result);
+        }
         result
     }
+
+    fn is_cached(&self, snippet: &CodeSnippet) -> bool {
+        self.cache.get(snippet).is_some()
+    }
 }
 
 impl OciRun {
@@ -210,71 +317,231 @@ impl OciRun {
     }
 
     pub fn run_snippets_of_content(&self, content: &str) -> Result<String> {
-        let ocirun_flag = "ocirun".to_string();
-        let helper = Snippets::create(content);
         let mut result = String::new();
         let mut begin: usize = 0;
-        let mut end: usize = 0;
-        for snippet in helper.snippets {
-            if !snippet.flags.contains(&ocirun_flag) {
-                end = snippet.all_range.end;
-                result.push_str(&content[begin..end]);
-                begin = end;
-                continue;
-            }
-            end = snippet.all_range.end;
-            result.push_str(&content[begin..end]);
-            begin = end;
-
-            if let Some(lang_config) = self.lang_config(&snippet.flags[0]) {
-                let config = Config::from(lang_config);
-                let code_snippet = CodeSnippet {
-                    expected: None,
-                    input: None,
-                    config,
-                    source: Source::String(snippet.get_source(content).to_string()),
-                };
-                let snippet_result = self.snippet_runner.run(&code_snippet);
-                let markdown = match snippet_result {
-                    Ok(content) => format!("\n```console,success\n{}```", content),
-                    Err(content) => format!("\n```console,error\n{}```", content),
-                };
+        for snippet in Snippets::create(content).executable() {
+            result.push_str(&content[begin..snippet.all_range.end]);
+            begin = snippet.all_range.end;
+            if let Some(markdown) = self.run_snippet(&snippet, content) {
                 result.push_str(&markdown);
             }
         }
-        result.push_str(&content[end..]);
+        result.push_str(&content[begin..]);
         Ok(result)
     }
+
+    // Runs the snippet returning the markdown to append after it,
+    // unless there is no config for its language
+    pub(crate) fn run_snippet(&self, snippet: &SnippetRef, content: &str) -> Option<String> {
+        let lang_config = self.lang_config(&snippet.flags[0])?;
+        let limits = match ResourceLimits::from_attributes(|name| snippet.flag_value(name)) {
+            Ok(limits) => self
+                .resource_limits
+                .overridden_by(&lang_config.limits)
+                .overridden_by(&limits),
+            Err(e) => {
+                self.log(
+                    LogLevel::Normal,
+                    format!(
+                        "Warning: ocirun `{}` snippet not run: {e}",
+                        lang_config.name
+                    ),
+                );
+                return Some(self.console_fence("error", &e.to_string()));
+            }
+        };
+        let code_snippet = CodeSnippet {
+            id: Some(self.report.location()),
+            expected: snippet.expected.as_ref().map(|expected| {
+                expected
+                    .clone()
+                    .map(|range| Source::String(content[range].to_string()))
+                    .map_err(|range| Source::String(content[range].to_string()))
+            }),
+            input: None,
+            config: Config {
+                network: Some(
+                    lang_config
+                        .network
+                        .clone()
+                        .unwrap_or(self.default_network.clone()),
+                ),
+                limits,
+                security: self.security.clone(),
+                runtime: self.runtime.clone(),
+                ..Config::from(lang_config)
+            },
+            source: Source::String(snippet.get_source(content).to_string()),
+        };
+        if self.skip {
+            return Some(self.console_fence("placeholder", SKIPPED));
+        }
+        if self.mode == Mode::DryRun {
+            let invocations = dry_run_invocations(&self.engine, &code_snippet, &self.secrets);
+            for invocation in &invocations {
+                self.log(LogLevel::Normal, format!("Dry run: {invocation}"));
+            }
+            let commands: Vec<String> = invocations.iter().map(|it| format!("$ {it}\n")).collect();
+            return Some(format!("\n```console,dry-run\n{}```", commands.concat()));
+        }
+        let digest = snippet_digest(
+            &code_snippet.config.image,
+            &code_snippet.config.command.join(" "),
+            &snippet.flags,
+            snippet.get_source(content),
+        );
+        let what = format!("ocirun `{}` snippet", lang_config.name);
+        if let Some(message) = self.unapproved(&digest, &what) {
+            return Some(self.console_fence("placeholder", &message));
+        }
+        let cached = self.backend.is_cached(&code_snippet);
+        if self.cache_only && !cached {
+            return Some(self.console_fence("placeholder", NOT_EXECUTED));
+        }
+        self.log(
+            LogLevel::Verbose,
+            format!(
+                "Running `{}` snippet in image `{}`{}",
+                lang_config.name,
+                lang_config.image,
+                if cached { " (cached)" } else { "" }
+            ),
+        );
+        let command = lang_config.command.join(" ");
+        if let Some(error) = self.refusal(ExecutionKind::Snippet, &lang_config.image, &command) {
+            self.log(
+                LogLevel::Normal,
+                format!(
+                    "Warning: ocirun `{}` snippet refused in image `{}`: {error}",
+                    lang_config.name, lang_config.image
+                ),
+            );
+            return Some(self.console_fence("error", &error));
+        }
+        self.progress
+            .running(&lang_config.image, &format!("{} snippet", lang_config.name));
+        let started = Instant::now();
+        let result = self.backend.run_snippet(&code_snippet);
+        self.progress.done(cached);
+        let rendered = match &result {
+            Ok(outcome) => Ok(outcome.stdout.clone()),
+            Err(error) => Err(error.output()),
+        };
+        // A snippet expected to fail succeeds by failing
+        let error = match &code_snippet.expected {
+            Some(expected) => self.expectation_failure(expected, &rendered),
+            None => rendered.as_ref().err().cloned(),
+        };
+        let outcome = match &result {
+            Ok(outcome) => Some(outcome.clone()),
+            Err(error) => error.outcome().cloned(),
+        };
+        self.record(Execution {
+            duration: started.elapsed(),
+            cache: match cached {
+                true => CacheStatus::Hit,
+                false => CacheStatus::Miss,
+            },
+            exit_code: outcome.as_ref().and_then(|outcome| outcome.exit_code),
+            success: error.is_none(),
+            error: error.clone(),
+            failure: match (&error, &result) {
+                (None, _) => None,
+                (Some(_), Err(failure)) if !matches!(code_snippet.expected, Some(Err(_))) => {
+                    Some(failure.failure_kind())
+                }
+                (Some(_), _) => Some(FailureKind::Assertion),
+            },
+            cache_key: Some(code_snippet.cache_key()),
+            outcome,
+            severity: lang_config.severity,
+            ..Execution::new(ExecutionKind::Snippet, &lang_config.image, &command)
+        });
+        if let Some(error) = &error {
+            self.log(
+                LogLevel::Normal,
+                format!(
+                    "Warning: ocirun `{}` snippet failed in image `{}`:\n{}",
+                    lang_config.name, lang_config.image, error
+                ),
+            );
+        }
+        let markdown = match rendered {
+            Ok(content) => self.console_fence("success", &content),
+            Err(content) => self.console_fence("error", &content),
+        };
+        Some(self.with_metadata(markdown))
+    }
+
+    // Tells how the result differs from the expected one, an empty
+    // `expected-error` block only expects the snippet to fail
+    fn expectation_failure(
+        &self,
+        expected: &Result<Source, Source>,
+        result: &Result<String, String>,
+    ) -> Option<String> {
+        let normalize = |output: &str| {
+            self.sanitize(&output.replace("\r\n", "\n"))
+                .trim_start_matches('\n')
+                .trim_end()
+                .to_string()
+        };
+        let (expected, actual) = match (expected, result) {
+            (Ok(expected), Ok(actual)) | (Err(expected), Err(actual)) => {
+                (normalize(&expected.get_content()), normalize(actual))
+            }
+            (Ok(_), Err(error)) => {
+                return Some(format!("expected to succeed, but failed:\n{error}"))
+            }
+            (Err(_), Ok(_)) => return Some("expected to fail, but succeeded".into()),
+        };
+        if result.is_err() && expected.is_empty() {
+            return None;
+        }
+        match expected == actual {
+            true => None,
+            false => Some(format!(
+                "output differs from the expected one\n--- expected\n{expected}\n+++ actual\n{actual}"
+            )),
+        }
+    }
+
+    fn console_fence(&self, kind: &str, content: &str) -> String {
+        let mut content =
+            format_whitespace(self.sanitize(content).into(), false, self.trailing_newlines);
+        // The closing fence must be on its own line
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        format!("\n```console,{}\n{}```", kind, content)
+    }
 }
 
 impl SnippetRunner for OciSnippetRunner {
-    fn run(&self, snippet: &CodeSnippet) -> Result<String, String> {
-        let mut args = vec!["create", "--rm", "-w", "/root", "-t", &snippet.config.image];
-        for arg in &snippet.config.command {
-            args.push(arg.as_str());
-        }
-
-        let container_id = Command::new(self.engine.as_str())
-            .stdin(Stdio::null())
-            .args(args)
-            .output()
-            .with_context(|| "Fail to create container")
-            .map(|output| {
-                String::from_utf8_lossy(&output.stdout)
-                    .trim_end()
-                    .to_string()
-            })
-            .unwrap();
+    fn run(&self, snippet: &CodeSnippet) -> ExecutionResult {
+        let started = Instant::now();
+        let created = self
+            .output(create_args(&snippet.config, &self.secrets))
+            .map_err(engine_unavailable)?;
+        // e.g. the image could not be pulled
+        if !created.status.success() {
+            return Err(ExecutionError::EngineFailed(ExecutionOutcome {
+                stderr: self
+                    .redactor
+                    .redact(&String::from_utf8_lossy(&created.stderr).replace("\r\n", "\n")),
+                exit_code: created.status.code(),
+                duration: started.elapsed(),
+                ..Default::default()
+            }));
+        }
+        let container_id = String::from_utf8_lossy(&created.stdout)
+            .trim_end()
+            .to_string();
 
         let source_path = snippet.source.get_path();
         let container_file = format!("{}:/root/source", container_id);
         let args = vec!["cp", source_path.to_str().unwrap(), container_file.as_str()];
-        let _copy_source_result = Command::new(self.engine.as_str())
-            .stdin(Stdio::null())
-            .args(args)
-            .output()
-            .with_context(|| "Fail to copy source")
-            .unwrap();
+        let _copy_source_result = self.output(args).map_err(engine_unavailable)?;
 
         let input_path = match &snippet.input {
             Some(source) => source.get_path(),
@@ -282,56 +549,167 @@ impl SnippetRunner for OciSnippetRunner {
         };
         let container_file = format!("{}:/root/input", container_id);
         let args = vec!["cp", input_path.to_str().unwrap(), container_file.as_str()];
-        let _copy_input_result = Command::new(self.engine.as_str())
-            .stdin(Stdio::null())
-            .args(args)
-            .output()
-            .with_context(|| "Fail to copy input")
-            .unwrap();
+        let _copy_input_result = self.output(args).map_err(engine_unavailable)?;
 
         let args = vec!["start", "-a", container_id.as_str()];
 
-        let output = Command::new(self.engine.as_str())
-            .stdin(Stdio::null())
-            .args(args)
-            .output()
-            .with_context(|| "Fail to run container")
-            .unwrap();
+        let mut command = Command::new(self.engine.as_str());
+        command.args(args);
+        echo_command(self.log_level, &command);
+        let echo = snippet
+            .id
+            .as_deref()
+            .filter(|_| self.stream_output)
+            .map(|prefix| (prefix, &self.redactor));
+        let output = capture(&mut command, &self.limits, echo, || {
+            kill_container(&self.engine, &container_id)
+        });
+        // Redacted here, so secrets never reach the cache
+        from_captured(output, started, &self.redactor)
+    }
+}
 
-        let stdout =
-            format_whitespace(String::from_utf8_lossy(&output.stdout), false).replace("\r\n", "\n");
+fn create_args(config: &Config, secrets: &Secrets) -> Vec<String> {
+    let mut args: Vec<String> = vec!["create".into(), "--rm".into()];
+    if let Some(network) = &config.network {
+        args.extend(["--network".into(), network.clone()]);
+    }
+    if let Some(runtime) = &config.runtime {
+        args.extend(["--runtime".into(), runtime.clone()]);
+    }
+    args.extend(config.limits.engine_args());
+    args.extend(config.security.engine_args());
+    args.extend(secrets.engine_args());
+    args.extend(["-w".into(), "/root".into(), config.image.clone()]);
+    args.extend(config.command.iter().cloned());
+    args
+}
 
-        match output.status.success() {
-            true => Ok(stdout),
-            false => Err(stdout),
-        }
+// The engine invocations running the snippet, the id of the created container
+// is only known once it is created
+fn dry_run_invocations(engine: &str, snippet: &CodeSnippet, secrets: &Secrets) -> Vec<String> {
+    let container = "<container>";
+    let input_path = match &snippet.input {
+        Some(source) => source.path(),
+        None => Path::new("/dev/null").to_path_buf(),
+    };
+    [
+        create_args(&snippet.config, secrets),
+        vec![
+            "cp".into(),
+            snippet.source.path().to_string_lossy().to_string(),
+            format!("{container}:/root/source"),
+        ],
+        vec![
+            "cp".into(),
+            input_path.to_string_lossy().to_string(),
+            format!("{container}:/root/input"),
+        ],
+        vec!["start".into(), "-a".into(), container.into()],
+    ]
+    .iter()
+    .map(|args| invocation(engine, args))
+    .collect()
+}
+
+fn engine_unavailable(error: io::Error) -> ExecutionError {
+    ExecutionError::EngineUnavailable(error.to_string())
+}
+
+// Appends the stderr of a failed run after its stdout, so compiler errors
+// and the like end up in the error fence instead of being discarded.
+pub(crate) fn with_stderr(mut stdout: String, stderr: &str) -> String {
+    if stderr.is_empty() {
+        return stdout;
+    }
+    if !stdout.is_empty() && !stdout.ends_with('\n') {
+        stdout.push('\n');
     }
+    stdout.push_str(stderr);
+    if !stdout.ends_with('\n') {
+        stdout.push('\n');
+    }
+    stdout
 }
 
+const EXPECTED_FLAG: &str = "expected";
+const EXPECTED_ERROR_FLAG: &str = "expected-error";
+
 #[derive(Debug)]
-struct SnippetRef {
-    flags: Vec<String>,
-    all_range: Range<usize>,
-    source_range: Range<usize>,
+pub(crate) struct SnippetRef {
+    pub flags: Vec<String>,
+    pub all_range: Range<usize>,
+    pub source_range: Range<usize>,
+    /// Source range of the output the snippet must print, or fail with
+    pub expected: Option<Result<Range<usize>, Range<usize>>>,
 }
 
 impl SnippetRef {
     pub fn get_source<'a>(&self, text: &'a str) -> &'a str {
         &text[self.source_range.clone()]
     }
+
+    pub fn is_executable(&self) -> bool {
+        self.has_flag("ocirun")
+    }
+
+    // Whether it holds the expected output of the snippet before it
+    pub fn is_expectation(&self) -> bool {
+        self.has_flag(EXPECTED_FLAG) || self.has_flag(EXPECTED_ERROR_FLAG)
+    }
+
+    fn has_flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|flag| flag == name)
+    }
+
+    // Value of a `name=value` flag, like the attributes of the directives
+    pub fn flag_value(&self, name: &str) -> Option<&str> {
+        self.flags
+            .iter()
+            .find_map(|flag| flag.strip_prefix(name)?.strip_prefix('='))
+    }
+}
+
+// A block flagged `expected` (or `expected-error`) right after an executable
+// snippet holds what the snippet must print (or fail with)
+fn expectation(
+    markdown: &str,
+    snippet: &SnippetRef,
+    next: &SnippetRef,
+) -> Option<Result<Range<usize>, Range<usize>>> {
+    if !snippet.is_executable()
+        || !markdown[snippet.all_range.end..next.all_range.start]
+            .trim()
+            .is_empty()
+    {
+        return None;
+    }
+    let range = next.source_range.clone();
+    match (
+        next.has_flag(EXPECTED_FLAG),
+        next.has_flag(EXPECTED_ERROR_FLAG),
+    ) {
+        (true, _) => Some(Ok(range)),
+        (_, true) => Some(Err(range)),
+        _ => None,
+    }
 }
 
 #[derive(Debug)]
-struct Snippets {
+pub(crate) struct Snippets {
     pub snippets: Vec<SnippetRef>,
+    /// Code blocks found, with or without flags
+    pub blocks: usize,
 }
 
 impl Snippets {
     pub fn create(markdown: &str) -> Snippets {
         let mut refs: Vec<SnippetRef> = vec![];
+        let mut blocks = 0;
         let mut captures = OCIRUN_SNIPPET.captures_iter(markdown);
         while let Some(begin_snippet) = captures.next() {
             if let Some(end_snippet) = captures.next() {
+                blocks += 1;
                 if let Some(flags) = begin_snippet.get(1) {
                     let begin = begin_snippet.get(0).unwrap().range();
                     let end = end_snippet.get(0).unwrap().range();
@@ -340,51 +718,178 @@ impl Snippets {
                         flags: flags.as_str().split(',').map(|it| it.to_string()).collect(),
                         all_range: range,
                         source_range: begin.end..end.start,
+                        expected: None,
                     };
                     refs.push(snippet);
                 }
             }
         }
-        Snippets { snippets: refs }
+        let expectations: Vec<_> = refs
+            .windows(2)
+            .map(|pair| expectation(markdown, &pair[0], &pair[1]))
+            .collect();
+        for (snippet, expected) in refs.iter_mut().zip(expectations) {
+            snippet.expected = expected;
+        }
+        Snippets {
+            snippets: refs,
+            blocks,
+        }
+    }
+
+    // Only the snippets flagged with `ocirun` are run
+    pub fn executable(self) -> impl Iterator<Item = SnippetRef> {
+        self.snippets.into_iter().filter(SnippetRef::is_executable)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
 
     use crate::{
-        ocirun::{LangConfig, OciRunConfig},
+        backend::{DirectiveRun, ExecutionBackend},
+        ocirun::{LangConfig, OciRunConfig, ResourceLimits, SecurityOptions},
+        outcome::{ExecutionError, ExecutionOutcome, ExecutionResult, LimitExceeded},
         snippet::OciSnippetRunner,
     };
 
-    use super::{CodeSnippet, CodeSnippetCache, Config, SnippetRunner, Snippets, Source};
+    use super::{
+        with_stderr, CachedRunner, CodeSnippet, CodeSnippetCache, Config, SnippetCache,
+        SnippetRunner, Snippets, Source,
+    };
 
     #[test]
     pub fn test_cache() {
         let snippet = CodeSnippet {
+            id: None,
             config: Config {
                 image: "alpine".to_string(),
                 command: vec!["ash".to_string()],
+                network: None,
+                limits: ResourceLimits::default(),
+                security: SecurityOptions::default(),
+                runtime: None,
             },
             input: None,
             expected: None,
             source: Source::String("echo ok".to_string()),
         };
         let cache = CodeSnippetCache::temp();
-        let expected: Result<String, String> = Result::Ok("ok".to_string());
+        let expected: ExecutionResult = Ok(ExecutionOutcome {
+            duration: Duration::from_millis(1500),
+            ..ExecutionOutcome::success("ok")
+        });
         let none = cache.get(&snippet);
         assert_eq!(none, None);
         cache.add(&snippet, &expected);
         let result = cache.get(&snippet).unwrap();
         assert_eq!(result, expected);
+        cache.invalidate(&snippet);
+        assert_eq!(cache.get(&snippet), None);
         cache.clear();
     }
 
+    #[test]
+    pub fn test_cache_key() {
+        let snippet = |config: Config| CodeSnippet {
+            id: None,
+            config,
+            input: None,
+            expected: None,
+            source: Source::String("echo ok".to_string()),
+        };
+        let config = Config {
+            image: "alpine".to_string(),
+            command: vec!["ash".to_string()],
+            network: None,
+            limits: ResourceLimits::default(),
+            security: SecurityOptions::default(),
+            runtime: None,
+        };
+        let key = snippet(config.clone()).cache_key();
+        assert_eq!(key, snippet(config.clone()).cache_key());
+        let changed = [
+            Config {
+                network: Some("bridge".into()),
+                ..config.clone()
+            },
+            Config {
+                limits: ResourceLimits {
+                    memory: Some("1g".into()),
+                    ..Default::default()
+                },
+                ..config.clone()
+            },
+            Config {
+                security: SecurityOptions {
+                    no_new_privileges: true,
+                    ..Default::default()
+                },
+                ..config.clone()
+            },
+            Config {
+                runtime: Some("runsc".into()),
+                ..config.clone()
+            },
+        ];
+        for config in changed {
+            assert_ne!(key, snippet(config).cache_key());
+        }
+    }
+
+    // Goes over its output limit every time it runs
+    struct ExceedingRunner(AtomicUsize);
+
+    impl SnippetRunner for ExceedingRunner {
+        fn run(&self, _snippet: &CodeSnippet) -> ExecutionResult {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Err(ExecutionError::LimitExceeded(LimitExceeded::Lines(3)))
+        }
+    }
+
+    #[test]
+    pub fn test_cache_skips_limit_exceeded() {
+        let path = std::env::temp_dir().join(".mdbook/ocirun-limits/");
+        let runner = CachedRunner {
+            cache: Box::new(CodeSnippetCache::new(path.display().to_string())),
+            runner: ExceedingRunner(AtomicUsize::new(0)),
+        };
+        let snippet = CodeSnippet {
+            id: None,
+            config: Config {
+                image: "alpine".to_string(),
+                command: vec!["ash".to_string()],
+                network: None,
+                limits: ResourceLimits::default(),
+                security: SecurityOptions::default(),
+                runtime: None,
+            },
+            input: None,
+            expected: None,
+            source: Source::String("yes".to_string()),
+        };
+        assert!(runner.run(&snippet).is_err());
+        assert!(!runner.is_cached(&snippet));
+        assert!(runner.run(&snippet).is_err());
+        assert_eq!(runner.runner.0.load(Ordering::SeqCst), 2);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    pub fn test_with_stderr() {
+        assert_eq!(with_stderr("out\n".into(), ""), "out\n");
+        assert_eq!(with_stderr("".into(), "error"), "error\n");
+        assert_eq!(with_stderr("out".into(), "error\n"), "out\nerror\n");
+    }
+
     #[test]
     pub fn test_run_snippet() {
         let runner = OciSnippetRunner::default();
         let snippet = CodeSnippet {
+            id: None,
             source: Source::String(
                 r#"
                 fn main() {
@@ -402,10 +907,14 @@ mod tests {
                     "-ec".to_string(),
                     "rustc source -o binary && ./binary < input".to_string(),
                 ],
+                network: None,
+                limits: ResourceLimits::default(),
+                security: SecurityOptions::default(),
+                runtime: None,
             },
         };
         let result = runner.run(&snippet);
-        assert_eq!(result, Result::Ok("Hello World!!!\n".into()));
+        assert_eq!(result.unwrap().stdout, "Hello World!!!\n");
     }
 
     #[test]
@@ -442,6 +951,58 @@ mod tests {
 
         let snippets = Snippets::create(markdown);
         assert_eq!(snippets.snippets.len(), 3);
+        assert_eq!(snippets.blocks, 3);
+    }
+
+    #[test]
+    pub fn test_expectation_failure() {
+        let preprocessor = OciRunConfig::default()
+            .create_preprocessor(".".into())
+            .unwrap();
+        let check = |expected: Result<&str, &str>, result: Result<&str, &str>| {
+            let expected = expected
+                .map(|it| Source::String(it.into()))
+                .map_err(|it| Source::String(it.into()));
+            let result = result.map(String::from).map_err(String::from);
+            preprocessor.expectation_failure(&expected, &result)
+        };
+        assert_eq!(check(Ok("\nhi\n"), Ok("hi\r\n")), None);
+        assert_eq!(check(Err("\n"), Err("boom\n")), None);
+        assert_eq!(check(Err("\nboom\n"), Err("boom\n")), None);
+        assert_eq!(
+            check(Ok("\nhi\n"), Ok("bye\n")),
+            Some("output differs from the expected one\n--- expected\nhi\n+++ actual\nbye".into())
+        );
+        assert_eq!(
+            check(Ok("\nhi\n"), Err("boom\n")),
+            Some("expected to succeed, but failed:\nboom\n".into())
+        );
+        assert_eq!(
+            check(Err("\n"), Ok("hi\n")),
+            Some("expected to fail, but succeeded".into())
+        );
+    }
+
+    #[test]
+    pub fn test_find_expected() {
+        let markdown = "```sh,ocirun\necho hi\n```\n\n```console,expected\nhi\n```\n\
+                        ```sh,ocirun\nexit 1\n```\n```console,expected-error\n```\n\
+                        ```sh,ocirun\necho untested\n```\ntext\n```console,expected\n```\n```\nplain\n```\n";
+        let snippets = Snippets::create(markdown);
+        assert_eq!(snippets.blocks, 7);
+        let expected: Vec<_> = snippets
+            .snippets
+            .iter()
+            .map(|snippet| match &snippet.expected {
+                Some(Ok(range)) => Some(Ok(&markdown[range.clone()])),
+                Some(Err(range)) => Some(Err(&markdown[range.clone()])),
+                None => None,
+            })
+            .collect();
+        assert_eq!(
+            expected,
+            vec![Some(Ok("\nhi\n")), None, Some(Err("\n")), None, None, None]
+        );
     }
 
     #[test]
@@ -467,12 +1028,82 @@ Hello World!!!
 ```
 after code
         "#;
-        let mut config = OciRunConfig::default();
-        config.langs = vec![LangConfig::rust()];
+        let config = OciRunConfig {
+            langs: vec![LangConfig::rust()],
+            ..Default::default()
+        };
         let result = config
             .create_preprocessor(Path::new("*").to_path_buf())
+            .unwrap()
             .run_snippets_of_content(markdown)
             .unwrap();
         assert_eq!(result, expected);
     }
+
+    // Prints what `println!` would, without any container
+    struct PrintlnBackend;
+
+    impl ExecutionBackend for PrintlnBackend {
+        // No directive in these tests, one reaching it fails with this error
+        fn run_directive(&self, _directive: &DirectiveRun) -> ExecutionResult {
+            Err(ExecutionError::EngineUnavailable(
+                "PrintlnBackend only runs snippets".into(),
+            ))
+        }
+
+        fn run_snippet(&self, snippet: &CodeSnippet) -> ExecutionResult {
+            let source = snippet.source.get_content();
+            match source.split_once("println!(\"") {
+                Some((_, rest)) => Ok(ExecutionOutcome::success(format!(
+                    "{}\n",
+                    rest.split('"').next().unwrap()
+                ))),
+                None => Err(ExecutionError::Failed(ExecutionOutcome::failure(
+                    "error: no output\n",
+                    1,
+                ))),
+            }
+        }
+
+        fn image_digest(&self, image: &str) -> Option<String> {
+            Some(format!("{image}@sha256:0123"))
+        }
+    }
+
+    #[test]
+    pub fn test_run_snippets_with_backend() {
+        let markdown = r#"```rust,ocirun
+fn main() { println!("Hello"); }
+```
+```console,expected
+Hello
+```
+```rust,ocirun
+fn main() {}
+```
+```console,expected
+Hello
+```
+"#;
+        let preprocessor = OciRunConfig {
+            langs: vec![LangConfig::rust()],
+            metadata: true,
+            ..Default::default()
+        }
+        .create_preprocessor(".".into())
+        .unwrap()
+        .with_backend(PrintlnBackend);
+        let result = preprocessor.run_snippets_of_content(markdown).unwrap();
+        assert!(result.starts_with(
+            "```rust,ocirun\nfn main() { println!(\"Hello\"); }\n```\n\
+             ```console,success\nHello\n```\n<!-- ocirun: rust@sha256:0123, "
+        ));
+        assert!(result.contains("```console,error\nerror: no output\n```\n"));
+        let failures = preprocessor.report.failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(
+            failures[0].error.as_deref(),
+            Some("expected to succeed, but failed:\nerror: no output\n")
+        );
+    }
 }
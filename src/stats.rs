@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[cfg(feature = "mdbook")]
+use mdbook::book::{Book, BookItem};
+use serde::Serialize;
+
+use crate::snippet::Snippets;
+use crate::OciRun;
+
+/// How much of the code of a chapter is run, and checked.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ChapterStats {
+    pub chapter: String,
+    pub code_blocks: usize,
+    /// Snippets run by ocirun, whose language has a config
+    pub executable: usize,
+    /// Executable snippets followed by their expected output
+    pub with_expected: usize,
+    /// Code blocks by language, expected outputs aside
+    pub languages: BTreeMap<String, usize>,
+    /// Lines of the executable snippets without expected output
+    pub untested: Vec<usize>,
+}
+
+/// Coverage of the code of a book by executions and expected outputs.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Stats {
+    pub chapters: Vec<ChapterStats>,
+}
+
+impl fmt::Display for ChapterStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} code blocks, {} executable, {} with expected output",
+            self.chapter, self.code_blocks, self.executable, self.with_expected
+        )?;
+        if !self.languages.is_empty() {
+            let languages: Vec<String> = self
+                .languages
+                .iter()
+                .map(|(language, count)| format!("{language} {count}"))
+                .collect();
+            write!(f, " ({})", languages.join(", "))?;
+        }
+        if !self.untested.is_empty() {
+            let lines: Vec<String> = self.untested.iter().map(ToString::to_string).collect();
+            write!(
+                f,
+                "\n  without expected output at lines {}",
+                lines.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for chapter in &self.chapters {
+            writeln!(f, "{chapter}")?;
+        }
+        let total =
+            |count: fn(&ChapterStats) -> usize| -> usize { self.chapters.iter().map(count).sum() };
+        writeln!(
+            f,
+            "\nTotal: {} code blocks, {} executable, {} with expected output",
+            total(|chapter| chapter.code_blocks),
+            total(|chapter| chapter.executable),
+            total(|chapter| chapter.with_expected)
+        )
+    }
+}
+
+impl OciRun {
+    pub fn stats_content(&self, content: &str, chapter: String) -> ChapterStats {
+        let snippets = Snippets::create(content);
+        let mut stats = ChapterStats {
+            chapter,
+            code_blocks: snippets.blocks,
+            ..Default::default()
+        };
+        for snippet in &snippets.snippets {
+            if snippet.is_expectation() {
+                continue;
+            }
+            *stats.languages.entry(snippet.flags[0].clone()).or_default() += 1;
+            if !snippet.is_executable() || self.lang_config(&snippet.flags[0]).is_none() {
+                continue;
+            }
+            stats.executable += 1;
+            match snippet.expected {
+                Some(_) => stats.with_expected += 1,
+                None => stats
+                    .untested
+                    .push(content[..snippet.all_range.start].matches('\n').count() + 1),
+            }
+        }
+        stats
+    }
+
+    #[cfg(feature = "mdbook")]
+    pub fn stats_book(&self, book: &Book) -> Stats {
+        let chapters = book
+            .iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(chapter) => Some(chapter),
+                _ => None,
+            })
+            .filter(|chapter| !self.is_ignored(chapter))
+            .map(|chapter| {
+                let name = chapter
+                    .source_path
+                    .as_ref()
+                    .map(|path| path.to_string_lossy().to_string())
+                    .unwrap_or_else(|| chapter.name.clone());
+                self.stats_content(&chapter.content, name)
+            })
+            .collect();
+        Stats { chapters }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::ChapterStats;
+    use crate::{ocirun::LangConfig, OciRunConfig};
+
+    #[test]
+    pub fn test_stats_content() {
+        let preprocessor = OciRunConfig {
+            langs: vec![LangConfig::rust()],
+            ..Default::default()
+        }
+        .create_preprocessor(".".into())
+        .unwrap();
+        let content = r#"# Chapter
+
+```rust,ocirun
+fn main() { println!("hi"); }
+```
+```console,expected
+hi
+```
+
+```rust,ocirun
+fn main() {}
+```
+
+```python,ocirun
+print("no config")
+```
+
+```toml
+[book]
+```
+"#;
+        let stats = preprocessor.stats_content(content, "chapter.md".into());
+        assert_eq!(
+            stats,
+            ChapterStats {
+                chapter: "chapter.md".into(),
+                code_blocks: 5,
+                executable: 2,
+                with_expected: 1,
+                languages: BTreeMap::from([
+                    ("python".into(), 1),
+                    ("rust".into(), 2),
+                    ("toml".into(), 1)
+                ]),
+                untested: vec![10],
+            }
+        );
+        assert_eq!(
+            stats.to_string(),
+            "chapter.md: 5 code blocks, 2 executable, 1 with expected output \
+             (python 1, rust 2, toml 1)\n  without expected output at lines 10"
+        );
+    }
+}
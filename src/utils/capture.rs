@@ -0,0 +1,489 @@
+use std::io::Read;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::Redactor;
+
+const CHUNK_SIZE: usize = 8 * 1024;
+
+pub const DEFAULT_MAX_OUTPUT_BYTES: usize = 1024 * 1024;
+
+pub const SPAWN_ERROR: &str = "Fail to spawn the container engine";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureLimits {
+    pub max_bytes: usize,
+    pub max_lines: Option<usize>,
+    pub timeout: Option<Duration>,
+}
+
+impl Default for CaptureLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: DEFAULT_MAX_OUTPUT_BYTES,
+            max_lines: None,
+            timeout: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LimitExceeded {
+    Bytes(usize),
+    Lines(usize),
+    Timeout(Duration),
+}
+
+impl LimitExceeded {
+    pub fn message(&self) -> String {
+        match self {
+            Self::Bytes(max) => format!("output limit exceeded: more than {max} bytes"),
+            Self::Lines(max) => format!("output limit exceeded: more than {max} lines"),
+            Self::Timeout(timeout) => {
+                format!("time limit exceeded: still running after {timeout:?}")
+            }
+        }
+    }
+}
+
+pub struct Captured {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub status: ExitStatus,
+    pub exceeded: Option<LimitExceeded>,
+}
+
+// Reads everything from `source` in a background thread, sending each chunk
+// through the returned channel, so the caller can stop listening at any time.
+fn stream<R: Read + Send + 'static>(mut source: R) -> mpsc::Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buffer = [0; CHUNK_SIZE];
+        while let Ok(size) = source.read(&mut buffer) {
+            if size == 0 || sender.send(buffer[..size].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+// Splits a stream into lines as it arrives, to print them prefixed and redacted
+struct Echo {
+    prefix: String,
+    redactor: Redactor,
+    pending: Vec<u8>,
+}
+
+impl Echo {
+    fn new((prefix, redactor): (&str, &Redactor)) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            redactor: redactor.clone(),
+            pending: vec![],
+        }
+    }
+
+    // The lines completed by `chunk`, the rest waits for the next one
+    fn lines(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(chunk);
+        let mut lines = vec![];
+        while let Some(end) = self.pending.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            lines.push(self.redactor.redact(line.trim_end()));
+        }
+        lines
+    }
+
+    fn print(&mut self, chunk: &[u8]) {
+        for line in self.lines(chunk) {
+            eprintln!("[{}] {line}", self.prefix);
+        }
+    }
+
+    fn finish(&mut self) {
+        if !self.pending.is_empty() {
+            self.print(b"\n");
+        }
+    }
+}
+
+// Keeps at most `max_bytes` of the stream, but drains it until the end
+// so the process never blocks on a full pipe.
+fn collect(
+    receiver: mpsc::Receiver<Vec<u8>>,
+    max_bytes: usize,
+    mut echo: Option<Echo>,
+) -> (Arc<Mutex<Vec<u8>>>, thread::JoinHandle<()>) {
+    let content = Arc::new(Mutex::new(vec![]));
+    let shared = content.clone();
+    let handle = thread::spawn(move || {
+        for chunk in receiver {
+            if let Some(echo) = echo.as_mut() {
+                echo.print(&chunk);
+            }
+            let mut content = shared.lock().unwrap();
+            let room = max_bytes.saturating_sub(content.len());
+            content.extend_from_slice(&chunk[..room.min(chunk.len())]);
+        }
+        if let Some(echo) = echo.as_mut() {
+            echo.finish();
+        }
+    });
+    (content, handle)
+}
+
+// Appends a chunk of stdout to what is kept of it, truncated to the limit it exceeds if any
+fn keep(
+    content: &mut Vec<u8>,
+    lines: &mut usize,
+    chunk: &[u8],
+    limits: &CaptureLimits,
+) -> Option<LimitExceeded> {
+    content.extend_from_slice(chunk);
+    if content.len() > limits.max_bytes {
+        content.truncate(limits.max_bytes);
+        return Some(LimitExceeded::Bytes(limits.max_bytes));
+    }
+    let max_lines = limits.max_lines?;
+    *lines += chunk.iter().filter(|&&byte| byte == b'\n').count();
+    if *lines <= max_lines {
+        return None;
+    }
+    // Nothing is kept with a limit of 0 lines
+    let end = max_lines
+        .checked_sub(1)
+        .and_then(|last| {
+            content
+                .iter()
+                .enumerate()
+                .filter(|(_, &byte)| byte == b'\n')
+                .nth(last)
+        })
+        .map(|(index, _)| index + 1)
+        .unwrap_or(0);
+    content.truncate(end);
+    Some(LimitExceeded::Lines(max_lines))
+}
+
+/// Runs `command` capturing its output while it is produced.
+///
+/// When one of the `limits` is exceeded, `on_exceeded` is called to stop
+/// whatever the process started (e.g. the container), the process itself is
+/// killed and the captured output is truncated.
+///
+/// With an `echo` prefix, the lines of stdout and stderr are also printed
+/// on stderr as soon as they are produced, prefixed with it and redacted.
+pub fn capture<F>(
+    command: &mut Command,
+    limits: &CaptureLimits,
+    echo: Option<(&str, &Redactor)>,
+    on_exceeded: F,
+) -> Result<Captured>
+where
+    F: FnOnce(),
+{
+    let mut child: Child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| SPAWN_ERROR)?;
+
+    let stdout = stream(child.stdout.take().expect("stdout is piped"));
+    let (stderr, stderr_handle) = collect(
+        stream(child.stderr.take().expect("stderr is piped")),
+        limits.max_bytes,
+        echo.map(Echo::new),
+    );
+    let mut echo = echo.map(Echo::new);
+
+    let deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+    let mut content = vec![];
+    let mut lines = 0;
+    let mut exceeded = None;
+    loop {
+        let chunk = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match stdout.recv_timeout(remaining) {
+                    Ok(chunk) => chunk,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        exceeded = limits.timeout.map(LimitExceeded::Timeout);
+                        break;
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            None => match stdout.recv() {
+                Ok(chunk) => chunk,
+                Err(_) => break,
+            },
+        };
+        if let Some(echo) = echo.as_mut() {
+            echo.print(&chunk);
+        }
+        exceeded = keep(&mut content, &mut lines, &chunk, limits);
+        if exceeded.is_some() {
+            break;
+        }
+    }
+
+    if let Some(echo) = echo.as_mut() {
+        echo.finish();
+    }
+    if exceeded.is_some() {
+        on_exceeded();
+        let _ = child.kill();
+    }
+    drop(stdout);
+
+    let status = child
+        .wait()
+        .with_context(|| "Fail to wait for the container engine")?;
+    // Leftovers of a stopped process may keep stderr open, so only wait for it
+    // when the process finished by itself
+    if exceeded.is_none() {
+        let _ = stderr_handle.join();
+    }
+    let stderr = stderr.lock().unwrap().clone();
+
+    Ok(Captured {
+        stdout: content,
+        stderr,
+        status,
+        exceeded,
+    })
+}
+
+/// Like [`capture`], on tokio rather than on threads of its own: the process is awaited
+/// while its output is read, and `on_exceeded` is only awaited when a limit is exceeded.
+///
+/// The timeout covers stderr too, which [`capture`] stops watching once stdout is closed.
+#[cfg(feature = "async")]
+pub async fn capture_async(
+    command: &mut tokio::process::Command,
+    limits: &CaptureLimits,
+    echo: Option<(&str, &Redactor)>,
+    on_exceeded: impl std::future::Future<Output = ()>,
+) -> Result<Captured> {
+    use tokio::io::AsyncReadExt;
+
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| SPAWN_ERROR)?;
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let (mut stdout_echo, mut stderr_echo) = (echo.map(Echo::new), echo.map(Echo::new));
+
+    let deadline = async {
+        match limits.timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending::<()>().await,
+        }
+    };
+    tokio::pin!(deadline);
+    let (mut stdout_buffer, mut stderr_buffer) = ([0; CHUNK_SIZE], [0; CHUNK_SIZE]);
+    let (mut content, mut errors) = (vec![], vec![]);
+    let (mut stdout_open, mut stderr_open) = (true, true);
+    let mut lines = 0;
+    let mut exceeded = None;
+    // Both are drained until the end, so the process never blocks on a full pipe
+    while stdout_open || stderr_open {
+        tokio::select! {
+            read = stdout.read(&mut stdout_buffer), if stdout_open => match read {
+                Ok(size) if size > 0 => {
+                    let chunk = &stdout_buffer[..size];
+                    if let Some(echo) = stdout_echo.as_mut() {
+                        echo.print(chunk);
+                    }
+                    exceeded = keep(&mut content, &mut lines, chunk, limits);
+                    if exceeded.is_some() {
+                        break;
+                    }
+                }
+                _ => stdout_open = false,
+            },
+            read = stderr.read(&mut stderr_buffer), if stderr_open => match read {
+                Ok(size) if size > 0 => {
+                    let chunk = &stderr_buffer[..size];
+                    if let Some(echo) = stderr_echo.as_mut() {
+                        echo.print(chunk);
+                    }
+                    let room = limits.max_bytes.saturating_sub(errors.len());
+                    errors.extend_from_slice(&chunk[..room.min(size)]);
+                }
+                _ => stderr_open = false,
+            },
+            _ = &mut deadline => {
+                exceeded = limits.timeout.map(LimitExceeded::Timeout);
+                break;
+            }
+        }
+    }
+
+    for echo in [stdout_echo.as_mut(), stderr_echo.as_mut()]
+        .into_iter()
+        .flatten()
+    {
+        echo.finish();
+    }
+    if exceeded.is_some() {
+        on_exceeded.await;
+        let _ = child.kill().await;
+    }
+    let status = child
+        .wait()
+        .await
+        .with_context(|| "Fail to wait for the container engine")?;
+
+    Ok(Captured {
+        stdout: content,
+        stderr: errors,
+        status,
+        exceeded,
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::process::Command;
+    use std::time::Duration;
+
+    use super::{capture, CaptureLimits, Echo, LimitExceeded};
+    use crate::utils::Redactor;
+
+    #[test]
+    pub fn test_capture() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo out; echo err >&2"]);
+        let captured = capture(&mut command, &CaptureLimits::default(), None, || {}).unwrap();
+        assert!(captured.status.success());
+        assert_eq!(captured.exceeded, None);
+        assert_eq!(captured.stdout, b"out\n");
+        assert_eq!(captured.stderr, b"err\n");
+    }
+
+    #[test]
+    pub fn test_capture_max_bytes() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "while true; do echo 42; done"]);
+        let mut stopped = false;
+        let limits = CaptureLimits {
+            max_bytes: 10,
+            ..Default::default()
+        };
+        let captured = capture(&mut command, &limits, None, || stopped = true).unwrap();
+        assert!(stopped);
+        assert_eq!(captured.exceeded, Some(LimitExceeded::Bytes(10)));
+        assert_eq!(captured.stdout, b"42\n42\n42\n4");
+    }
+
+    #[test]
+    pub fn test_capture_max_lines() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "while true; do echo 42; done"]);
+        let limits = CaptureLimits {
+            max_lines: Some(3),
+            ..Default::default()
+        };
+        let captured = capture(&mut command, &limits, None, || {}).unwrap();
+        assert_eq!(captured.exceeded, Some(LimitExceeded::Lines(3)));
+        assert_eq!(captured.stdout, b"42\n42\n42\n");
+
+        let limits = CaptureLimits {
+            max_lines: Some(0),
+            ..Default::default()
+        };
+        let captured = capture(&mut command, &limits, None, || {}).unwrap();
+        assert_eq!(captured.exceeded, Some(LimitExceeded::Lines(0)));
+        assert_eq!(captured.stdout, b"");
+    }
+
+    #[test]
+    pub fn test_capture_timeout() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo started; sleep 10"]);
+        let limits = CaptureLimits {
+            timeout: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        let captured = capture(&mut command, &limits, None, || {}).unwrap();
+        assert_eq!(
+            captured.exceeded,
+            Some(LimitExceeded::Timeout(Duration::from_millis(500)))
+        );
+        assert_eq!(captured.stdout, b"started\n");
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    pub async fn test_capture_async() {
+        use super::capture_async;
+
+        let mut command = tokio::process::Command::new("sh");
+        command.args(["-c", "echo out; echo err >&2"]);
+        let captured = capture_async(&mut command, &CaptureLimits::default(), None, async {})
+            .await
+            .unwrap();
+        assert!(captured.status.success());
+        assert_eq!(captured.exceeded, None);
+        assert_eq!(captured.stdout, b"out\n");
+        assert_eq!(captured.stderr, b"err\n");
+
+        let mut command = tokio::process::Command::new("sh");
+        command.args(["-c", "while true; do echo 42; done"]);
+        let mut stopped = false;
+        let limits = CaptureLimits {
+            max_lines: Some(3),
+            ..Default::default()
+        };
+        let captured = capture_async(&mut command, &limits, None, async { stopped = true })
+            .await
+            .unwrap();
+        assert!(stopped);
+        assert_eq!(captured.exceeded, Some(LimitExceeded::Lines(3)));
+        assert_eq!(captured.stdout, b"42\n42\n42\n");
+
+        let mut command = tokio::process::Command::new("sh");
+        command.args(["-c", "echo started; sleep 10"]);
+        let limits = CaptureLimits {
+            timeout: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        let captured = capture_async(&mut command, &limits, None, async {})
+            .await
+            .unwrap();
+        assert_eq!(
+            captured.exceeded,
+            Some(LimitExceeded::Timeout(Duration::from_millis(500)))
+        );
+        assert_eq!(captured.stdout, b"started\n");
+    }
+
+    #[test]
+    pub fn test_echo_lines() {
+        let mut echo = Echo::new(("chapter.md:3", &Redactor::default()));
+        assert_eq!(echo.lines(b"compiling"), Vec::<String>::new());
+        assert_eq!(
+            echo.lines(b" a\r\ncompiling b\nrun"),
+            vec!["compiling a", "compiling b"]
+        );
+        assert_eq!(echo.lines(b"ning\n"), vec!["running"]);
+
+        let redactor = Redactor::default().with_values(["sk-s3cr3t".to_string()]);
+        let mut echo = Echo::new(("chapter.md:3", &redactor));
+        assert_eq!(echo.lines(b"key=sk-s3cr3t\n"), vec!["key=[REDACTED]"]);
+    }
+}
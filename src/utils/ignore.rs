@@ -0,0 +1,65 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+// Patterns of chapters to ignore, one per line, at the book root
+const IGNORE_FILE: &str = ".ocirunignore";
+
+/// Chapters passed through untouched, matched by their path under the book sources.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoredChapters {
+    globs: GlobSet,
+}
+
+impl IgnoredChapters {
+    // The patterns of the config, then the ones of `.ocirunignore` without its blank and `#` lines
+    pub fn new(patterns: &[String], root: &Path) -> Result<Self> {
+        let file = fs::read_to_string(root.join(IGNORE_FILE)).unwrap_or_default();
+        let file_patterns = file
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns.iter().map(String::as_str).chain(file_patterns) {
+            builder.add(
+                Glob::new(pattern)
+                    .with_context(|| format!("Invalid ignore pattern `{pattern}`"))?,
+            );
+        }
+        Ok(Self {
+            globs: builder.build()?,
+        })
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        self.globs.is_match(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::IgnoredChapters;
+
+    #[test]
+    pub fn test_ignored_chapters() {
+        let root = std::env::temp_dir().join(format!("ocirun-ignore-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join(".ocirunignore"),
+            "# broken on purpose\n\nlegacy/*.md\n",
+        )
+        .unwrap();
+        let patterns = vec!["drafts/**".to_string(), "appendix-raw.md".to_string()];
+        let ignored = IgnoredChapters::new(&patterns, &root).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(ignored.matches(Path::new("drafts/next/chapter.md")));
+        assert!(ignored.matches(Path::new("appendix-raw.md")));
+        assert!(ignored.matches(Path::new("legacy/old.md")));
+        assert!(!ignored.matches(Path::new("intro.md")));
+        assert!(IgnoredChapters::new(&["[".to_string()], Path::new(".")).is_err());
+    }
+}
@@ -4,6 +4,8 @@ use mdbook::book::Book;
 use mdbook::book::BookItem;
 use mdbook::book::Chapter;
 
+/// Calls `func` on every chapter of the book, sub-chapters included, in order,
+/// stopping at the first error.
 pub fn map_chapter<F>(book: &mut Book, func: &mut F) -> Result<()>
 where
     F: FnMut(&mut Chapter) -> Result<()>,
@@ -31,3 +33,33 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use mdbook::book::{Book, BookItem, Chapter};
+
+    use super::map_chapter;
+
+    #[test]
+    pub fn test_map_chapter() {
+        let mut intro = Chapter::new("Intro", String::new(), "intro.md", vec![]);
+        let usage = Chapter::new("Usage", String::new(), "intro/usage.md", vec![]);
+        intro.sub_items.push(BookItem::Chapter(usage));
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(intro));
+        book.push_item(BookItem::Separator);
+
+        let mut names = vec![];
+        map_chapter(&mut book, &mut |chapter| {
+            names.push(chapter.name.clone());
+            chapter.content = format!("# {}\n", chapter.name);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(names, vec!["Intro", "Usage"]);
+        let BookItem::Chapter(intro) = &book.sections[0] else {
+            panic!("not a chapter");
+        };
+        assert_eq!(intro.content, "# Intro\n");
+    }
+}
@@ -1,5 +1,19 @@
+pub mod capture;
+pub mod ignore;
+#[cfg(feature = "mdbook")]
 pub mod map_chapter;
+pub mod redact;
+pub mod secrets;
 pub mod string;
 
+pub use capture::capture;
+#[cfg(feature = "async")]
+pub use capture::capture_async;
+pub use capture::CaptureLimits;
+pub use ignore::IgnoredChapters;
+#[cfg(feature = "mdbook")]
 pub use map_chapter::map_chapter;
+pub use redact::Redactor;
+pub use secrets::Secrets;
 pub use string::format_whitespace;
+pub use string::sanitize;
@@ -0,0 +1,69 @@
+use regex::Regex;
+
+use crate::ocirun::RedactRule;
+
+const MASK: &str = "[REDACTED]";
+
+/// Masks secrets in the outputs before they are rendered or cached.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    // Environment variables are resolved once, unset or empty ones have nothing to hide
+    pub fn new(rules: &[RedactRule]) -> Self {
+        let patterns = rules
+            .iter()
+            .filter_map(|rule| match rule {
+                RedactRule::Env(name) => std::env::var(name)
+                    .ok()
+                    .filter(|value| !value.is_empty())
+                    .map(|value| Regex::new(&regex::escape(&value)).unwrap()),
+                RedactRule::Pattern(pattern) => Some(pattern.clone()),
+            })
+            .collect();
+        Self { patterns }
+    }
+
+    /// Masks these values too, like the ones of the secrets.
+    pub fn with_values(mut self, values: impl IntoIterator<Item = String>) -> Self {
+        self.patterns.extend(
+            values
+                .into_iter()
+                .filter(|value| !value.is_empty())
+                .map(|value| Regex::new(&regex::escape(&value)).unwrap()),
+        );
+        self
+    }
+
+    pub fn redact(&self, str: &str) -> String {
+        self.patterns.iter().fold(str.to_string(), |str, pattern| {
+            pattern.replace_all(&str, MASK).to_string()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Redactor;
+    use crate::ocirun::RedactRule;
+
+    #[test]
+    pub fn test_redact() {
+        std::env::set_var("OCIRUN_TEST_REDACT_TOKEN", "s3cr3t.value");
+        let rules: Vec<RedactRule> = vec![
+            "OCIRUN_TEST_REDACT_TOKEN".parse().unwrap(),
+            "OCIRUN_TEST_REDACT_UNSET".parse().unwrap(),
+            "re:sk-[A-Za-z0-9]+".parse().unwrap(),
+        ];
+        let redactor = Redactor::new(&rules);
+        assert_eq!(
+            redactor.redact("token=s3cr3t.value key=sk-abc123 s3cr3t-value"),
+            "token=[REDACTED] key=[REDACTED] s3cr3t-value"
+        );
+        assert_eq!(Redactor::default().redact("sk-abc123"), "sk-abc123");
+        let redactor = Redactor::default().with_values(["p4ss".to_string(), "".to_string()]);
+        assert_eq!(redactor.redact("user:p4ss"), "user:[REDACTED]");
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::ocirun::SecretSource;
+
+// Where the file secrets are mounted in the containers
+const SECRETS_DIR: &str = "/run/secrets";
+
+/// The secrets given to every container, read once from the host.
+///
+/// Their values only reach the engine through its environment, never its arguments,
+/// so they are neither printed with the commands nor part of any cache key.
+#[derive(Clone, Default)]
+pub struct Secrets {
+    env: Vec<(String, String)>,
+    files: Vec<(String, PathBuf)>,
+}
+
+impl Secrets {
+    // Unset or empty environment variables have nothing to give, missing files are left
+    // to the engine which fails to mount them
+    pub fn new(sources: &BTreeMap<String, SecretSource>, root_path: &Path) -> Self {
+        let mut secrets = Self::default();
+        for (name, source) in sources {
+            match source {
+                SecretSource::Env(variable) => {
+                    if let Some(value) = std::env::var(variable).ok().filter(|it| !it.is_empty()) {
+                        secrets.env.push((name.clone(), value));
+                    }
+                }
+                SecretSource::File(path) => {
+                    secrets.files.push((name.clone(), root_path.join(path)))
+                }
+            }
+        }
+        secrets
+    }
+
+    /// What to mask in the outputs: the values of the variables and the contents of the files.
+    pub fn values(&self) -> Vec<String> {
+        let files = self
+            .files
+            .iter()
+            .filter_map(|(_, path)| fs::read_to_string(path).ok())
+            .map(|content| content.trim().to_string());
+        self.env
+            .iter()
+            .map(|(_, value)| value.clone())
+            .chain(files)
+            .filter(|value| !value.is_empty())
+            .collect()
+    }
+
+    // Arguments of `run` and `create`, the variables are only named so the engine
+    // takes their values from its environment
+    pub(crate) fn engine_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        for (name, _) in &self.env {
+            args.extend(["--env".into(), name.clone()]);
+        }
+        for (name, path) in &self.files {
+            args.extend([
+                "-v".into(),
+                format!("{}:{SECRETS_DIR}/{name}:ro", path.display()),
+            ]);
+        }
+        args
+    }
+
+    /// The mounted files, by the names of their secrets, e.g. for the audit.
+    pub fn files(&self) -> impl Iterator<Item = (&str, &Path)> {
+        self.files
+            .iter()
+            .map(|(name, path)| (name.as_str(), path.as_path()))
+    }
+
+    // Gives the values to the engine command
+    pub(crate) fn apply(&self, command: &mut Command) {
+        command.envs(self.env.iter().map(|(name, value)| (name, value)));
+    }
+}
+
+// Only the names, the values must not end up in a log
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secrets")
+            .field(
+                "env",
+                &self.env.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            )
+            .field(
+                "files",
+                &self.files.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use std::process::Command;
+
+    use super::Secrets;
+    use crate::ocirun::SecretSource;
+
+    #[test]
+    pub fn test_secrets() {
+        std::env::set_var("OCIRUN_TEST_SECRET_KEY", "sk-s3cr3t");
+        let sources = BTreeMap::from([
+            (
+                "API_KEY".to_string(),
+                SecretSource::Env("OCIRUN_TEST_SECRET_KEY".into()),
+            ),
+            (
+                "UNSET".to_string(),
+                SecretSource::Env("OCIRUN_TEST_SECRET_UNSET".into()),
+            ),
+            (
+                "CERT".to_string(),
+                SecretSource::File("certs/cert.pem".into()),
+            ),
+        ]);
+        let secrets = Secrets::new(&sources, Path::new("/book"));
+        assert_eq!(
+            secrets.engine_args(),
+            [
+                "--env",
+                "API_KEY",
+                "-v",
+                "/book/certs/cert.pem:/run/secrets/CERT:ro"
+            ]
+        );
+        assert_eq!(
+            secrets.files().collect::<Vec<_>>(),
+            [("CERT", Path::new("/book/certs/cert.pem"))]
+        );
+        assert_eq!(secrets.values(), ["sk-s3cr3t"]);
+        assert!(!format!("{secrets:?}").contains("sk-s3cr3t"));
+        let mut command = Command::new("docker");
+        secrets.apply(&mut command);
+        assert_eq!(
+            command.get_envs().collect::<Vec<_>>(),
+            [("API_KEY".as_ref(), Some("sk-s3cr3t".as_ref()))]
+        );
+    }
+}
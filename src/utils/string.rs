@@ -1,31 +1,77 @@
 use std::borrow::Cow;
-// Some progams output linebreaks in UNIX format,
-// this can cause problems on Windows if for any reason
-// the user is expecting consistent linebreaks,
-// e.g. they run the resulting markdown through a validation tool.
-//
-// So this function simply replaces all linebreaks with Windows linebreaks.
-#[cfg(target_family = "windows")]
-fn format_whitespace(str: Cow<'_, str>, inline: bool) -> String {
-    let str = match inline {
-        // When running inline it is undeseriable to have trailing whitespace
-        true => str.trim_end(),
-        false => str.as_ref(),
-    };
 
-    let mut res = str.lines().collect::<Vec<_>>().join("\r\n");
-    if !inline && !res.is_empty() {
-        res.push_str("\r\n");
+use crate::ocirun::TrailingNewlines;
+
+// Some progams output linebreaks in Windows format,
+// so every linebreak is normalized to the UNIX format first,
+// then the trailing linebreaks are handled following the configured policy.
+pub fn format_whitespace(str: Cow<'_, str>, inline: bool, policy: TrailingNewlines) -> String {
+    let str = str.replace("\r\n", "\n");
+    if inline {
+        // When running inline it is undeseriable to have trailing whitespace
+        return str.trim_end().to_string();
     }
+    match policy {
+        TrailingNewlines::Preserve => str,
+        TrailingNewlines::Strip => str.trim_end_matches('\n').to_string(),
+        TrailingNewlines::Single => {
+            let mut res = str.trim_end_matches('\n').to_string();
+            if !res.is_empty() {
+                res.push('\n');
+            }
+            res
+        }
+    }
+}
+
+const BOM: char = '\u{feff}';
 
-    return res;
+// Characters rendering as nothing which still break markdown tables and fences
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00ad}'
+            | '\u{200b}'..='\u{200f}'
+            | '\u{202a}'..='\u{202e}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{2069}'
+            | BOM
+    ) || (c.is_control() && c != '\n' && c != '\t')
 }
 
-#[cfg(any(target_family = "unix", target_family = "other"))]
-pub fn format_whitespace(str: Cow<'_, str>, inline: bool) -> String {
-    match inline {
-        // Wh;n running inline it is undeseriable to have trailing whitespace
-        true => str.trim_end().to_string(),
-        false => str.to_string(),
+// Tools like the Windows ones may emit byte order marks in the middle of the output,
+// so every one of them is removed, not only the leading one.
+pub fn sanitize(str: &str, strip_bom: bool, strip_invisible: bool) -> String {
+    str.chars()
+        .filter(|&c| !(strip_bom && c == BOM || strip_invisible && is_invisible(c)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_whitespace, sanitize};
+    use crate::ocirun::TrailingNewlines;
+
+    #[test]
+    pub fn test_format_whitespace() {
+        let format = |str: &str, inline, policy| format_whitespace(str.into(), inline, policy);
+        let output = "a\r\nb  \n\n\n";
+        assert_eq!(format(output, true, TrailingNewlines::Preserve), "a\nb");
+        assert_eq!(
+            format(output, false, TrailingNewlines::Preserve),
+            "a\nb  \n\n\n"
+        );
+        assert_eq!(format(output, false, TrailingNewlines::Single), "a\nb  \n");
+        assert_eq!(format(output, false, TrailingNewlines::Strip), "a\nb  ");
+        assert_eq!(format("\n\n", false, TrailingNewlines::Single), "");
+    }
+
+    #[test]
+    pub fn test_sanitize() {
+        let output = "\u{feff}a|b\u{200b}|\x1b[0mc\t\n\u{feff}d\n";
+        assert_eq!(sanitize(output, false, false), output);
+        assert_eq!(sanitize(output, true, false), "a|b\u{200b}|\x1b[0mc\t\nd\n");
+        assert_eq!(sanitize(output, true, true), "a|b|[0mc\t\nd\n");
+        assert_eq!(sanitize(output, false, true), "a|b|[0mc\t\nd\n");
     }
 }
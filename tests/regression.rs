@@ -52,6 +52,7 @@ macro_rules! add_dir {
 
                 let actual_output_content = OciRunConfig::default()
                     .create_preprocessor(Path::new(".").to_path_buf())
+                    .unwrap()
                     .run_on_content(&input_content, &working_dir)
                     .expect("unable to execute ocirun");
 